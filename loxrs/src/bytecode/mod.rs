@@ -0,0 +1,285 @@
+//! The `.loxc` file format, for compiling scripts once and running them
+//! later without the source.
+//!
+//! Everything is little-endian. A file is the magic bytes, a `u16` format
+//! version and then the top-level script's function prototype:
+//!
+//! ```text
+//! function  := name arity:u32 upvalue_count:u32 chunk
+//! name      := 0:u8 | 1:u8 string
+//! chunk     := code lines spans constants
+//! code      := len:u32 byte*
+//! lines     := len:u32 (start:u32 line:u32)*
+//! spans     := len:u32 (start:u32 span_start:u32 span_end:u32)*
+//! constants := len:u32 constant*
+//! constant  := 0:u8                  nil
+//!            | 1:u8 | 2:u8           false, true
+//!            | 3:u8 f64              number
+//!            | 4:u8 string
+//!            | 5:u8 function         a nested function's prototype
+//! string    := len:u32 utf8-byte*
+//! ```
+//!
+//! The opcodes are written as they're numbered in `OpCode`, so changing that
+//! enum means bumping `VERSION`.
+
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, LineRun, SpanRun};
+use crate::error::{Error, Result};
+use crate::memory::Heap;
+use crate::object::{Obj, ObjFunction, ObjRef};
+use crate::value::Value;
+use crate::verifier::NESTING_MAX;
+use crate::vm::{Script, VM};
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+pub const VERSION: u16 = 1;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+/// Whether `bytes` look like a `.loxc` file rather than source.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Serialize a compiled script.
+pub fn save(vm: &VM, script: &Script) -> Result<Vec<u8>> {
+    vm.check_owner(script)?;
+    let mut writer = Writer {
+        heap: &vm.heap,
+        out: Vec::new(),
+    };
+    writer.out.extend_from_slice(MAGIC);
+    writer.out.extend_from_slice(&VERSION.to_le_bytes());
+    writer.function(script.function)?;
+    Ok(writer.out)
+}
+
+/// Load a script saved by `save` onto the VM's heap, verifying its bytecode.
+pub fn load(vm: &mut VM, bytes: &[u8]) -> Result<Script> {
+    let mut reader = Reader {
+        heap: &mut vm.heap,
+        bytes,
+        position: 0,
+        depth: 0,
+    };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(invalid("not a .loxc file"));
+    }
+    let version = u16::from_le_bytes([reader.u8()?, reader.u8()?]);
+    if version != VERSION {
+        return Err(invalid(&format!(
+            "unsupported version {} (expected {})",
+            version, VERSION
+        )));
+    }
+
+    // The heap's own allocation never collects, so nothing loaded here can
+    // be freed before the script is pinned.
+    let function = reader.function()?;
+    if reader.position != bytes.len() {
+        return Err(invalid("trailing bytes after the script"));
+    }
+    vm.load_function(function)
+}
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidBytecode(message.to_string())
+}
+
+struct Writer<'a> {
+    heap: &'a Heap,
+    out: Vec<u8>,
+}
+
+impl<'a> Writer<'a> {
+    fn function(&mut self, reference: ObjRef) -> Result<()> {
+        let function = self
+            .heap
+            .as_function(reference)
+            .ok_or_else(|| invalid("expected a function"))?;
+
+        match function.name {
+            Some(name) => {
+                self.u8(1);
+                self.string(self.heap.string_chars(name));
+            }
+            None => self.u8(0),
+        }
+        self.u32(function.arity)?;
+        self.u32(function.upvalue_count)?;
+        self.chunk(&function.chunk)
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) -> Result<()> {
+        self.u32(chunk.code.len())?;
+        self.out.extend_from_slice(&chunk.code);
+
+        self.u32(chunk.lines.len())?;
+        for run in chunk.lines.iter() {
+            self.u32(run.start)?;
+            self.u32(run.line)?;
+        }
+
+        self.u32(chunk.spans.len())?;
+        for run in chunk.spans.iter() {
+            self.u32(run.start)?;
+            self.u32(run.span.start)?;
+            self.u32(run.span.end)?;
+        }
+
+        self.u32(chunk.constants.len())?;
+        for &constant in chunk.constants.iter() {
+            self.constant(constant)?;
+        }
+        Ok(())
+    }
+
+    fn constant(&mut self, constant: Value) -> Result<()> {
+        match constant {
+            Value::Nil => self.u8(TAG_NIL),
+            Value::Bool(false) => self.u8(TAG_FALSE),
+            Value::Bool(true) => self.u8(TAG_TRUE),
+            Value::Number(number) => {
+                self.u8(TAG_NUMBER);
+                self.out.extend_from_slice(&number.to_le_bytes());
+            }
+            Value::Obj(reference) => match self.heap.get(reference) {
+                Obj::String(string) => {
+                    self.u8(TAG_STRING);
+                    self.string(&string.chars);
+                }
+                Obj::Function(_) => {
+                    self.u8(TAG_FUNCTION);
+                    self.function(reference)?;
+                }
+                _ => return Err(invalid("only strings and functions can be constants")),
+            },
+        }
+        Ok(())
+    }
+
+    fn string(&mut self, chars: &str) {
+        // A string can't be longer than the source it came from, which
+        // `u32` covers.
+        self.out
+            .extend_from_slice(&(chars.len() as u32).to_le_bytes());
+        self.out.extend_from_slice(chars.as_bytes());
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.out.push(value);
+    }
+
+    fn u32(&mut self, value: usize) -> Result<()> {
+        let value = u32::try_from(value).map_err(|_| invalid("value too large for the format"))?;
+        self.out.extend_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    heap: &'a mut Heap,
+    bytes: &'a [u8],
+    position: usize,
+    /// How many functions deep the reader is, so a malformed file can't
+    /// recurse until the stack overflows.
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn function(&mut self) -> Result<ObjRef> {
+        if self.depth > NESTING_MAX {
+            return Err(invalid("functions nested too deeply"));
+        }
+        self.depth += 1;
+
+        let name = match self.u8()? {
+            0 => None,
+            1 => Some(self.string()?),
+            tag => return Err(invalid(&format!("bad function name tag {}", tag))),
+        };
+        let mut function = ObjFunction::new(name);
+        function.arity = self.u32()?;
+        function.upvalue_count = self.u32()?;
+        function.chunk = Rc::new(self.chunk()?);
+        self.depth -= 1;
+        Ok(self.heap.alloc(Obj::Function(function)))
+    }
+
+    fn chunk(&mut self) -> Result<Chunk> {
+        let mut chunk = Chunk::new();
+
+        let length = self.u32()?;
+        chunk.code = self.take(length)?.to_vec();
+
+        for _ in 0..self.u32()? {
+            let start = self.u32()?;
+            let line = self.u32()?;
+            chunk.lines.push(LineRun { start, line });
+        }
+
+        for _ in 0..self.u32()? {
+            let start = self.u32()?;
+            let span = self.u32()?..self.u32()?;
+            chunk.spans.push(SpanRun { start, span });
+        }
+
+        for _ in 0..self.u32()? {
+            let constant = self.constant()?;
+            chunk.constants.push(constant);
+        }
+        Ok(chunk)
+    }
+
+    fn constant(&mut self) -> Result<Value> {
+        match self.u8()? {
+            TAG_NIL => Ok(Value::Nil),
+            TAG_FALSE => Ok(Value::Bool(false)),
+            TAG_TRUE => Ok(Value::Bool(true)),
+            TAG_NUMBER => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(self.take(8)?);
+                Ok(Value::Number(f64::from_le_bytes(bytes)))
+            }
+            TAG_STRING => Ok(Value::Obj(self.string()?)),
+            TAG_FUNCTION => Ok(Value::Obj(self.function()?)),
+            tag => Err(invalid(&format!("bad constant tag {}", tag))),
+        }
+    }
+
+    fn string(&mut self) -> Result<ObjRef> {
+        let length = self.u32()?;
+        let chars = std::str::from_utf8(self.take(length)?)
+            .map_err(|_| invalid("string constant isn't UTF-8"))?;
+        Ok(self.heap.intern(chars))
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| invalid("unexpected end of file"))?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes) as usize)
+    }
+}
@@ -1,16 +1,61 @@
 use std::convert::TryFrom;
+use std::ops::Range;
 
+use crate::diagnostic::SourceId;
 use crate::error::Error;
 use crate::value::Value;
 
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum OpCode {
     OpConstant,
+    OpConstantLong,
+    OpNil,
+    OpTrue,
+    OpFalse,
+    OpPop,
+    OpGetLocal,
+    OpSetLocal,
+    OpGetGlobal,
+    OpDefineGlobal,
+    OpSetGlobal,
+    OpGetUpvalue,
+    OpSetUpvalue,
+    OpGetProperty,
+    OpSetProperty,
+    OpGetSuper,
+    OpEqual,
+    OpGreater,
+    OpLess,
     OpAdd,
     OpSubtract,
     OpMultiply,
     OpDivide,
+    OpNot,
     OpNegate,
+    OpPrint,
+    OpJump,
+    OpJumpIfFalse,
+    OpLoop,
+    OpCall,
+    OpInvoke,
+    OpSuperInvoke,
+    OpClosure,
+    OpCloseUpvalue,
     OpReturn,
+    OpClass,
+    OpInherit,
+    OpMethod,
+    OpGetGlobalLong,
+    OpDefineGlobalLong,
+    OpSetGlobalLong,
+    OpGetPropertyLong,
+    OpSetPropertyLong,
+    OpGetSuperLong,
+    OpInvokeLong,
+    OpSuperInvokeLong,
+    OpClosureLong,
+    OpClassLong,
+    OpMethodLong,
 }
 
 impl TryFrom<u8> for OpCode {
@@ -19,44 +64,189 @@ impl TryFrom<u8> for OpCode {
     fn try_from(value: u8) -> Result<Self, Error> {
         match value {
             0 => Ok(OpCode::OpConstant),
-            1 => Ok(OpCode::OpAdd),
-            2 => Ok(OpCode::OpSubtract),
-            3 => Ok(OpCode::OpMultiply),
-            4 => Ok(OpCode::OpDivide),
-            5 => Ok(OpCode::OpNegate),
-            6 => Ok(OpCode::OpReturn),
+            1 => Ok(OpCode::OpConstantLong),
+            2 => Ok(OpCode::OpNil),
+            3 => Ok(OpCode::OpTrue),
+            4 => Ok(OpCode::OpFalse),
+            5 => Ok(OpCode::OpPop),
+            6 => Ok(OpCode::OpGetLocal),
+            7 => Ok(OpCode::OpSetLocal),
+            8 => Ok(OpCode::OpGetGlobal),
+            9 => Ok(OpCode::OpDefineGlobal),
+            10 => Ok(OpCode::OpSetGlobal),
+            11 => Ok(OpCode::OpGetUpvalue),
+            12 => Ok(OpCode::OpSetUpvalue),
+            13 => Ok(OpCode::OpGetProperty),
+            14 => Ok(OpCode::OpSetProperty),
+            15 => Ok(OpCode::OpGetSuper),
+            16 => Ok(OpCode::OpEqual),
+            17 => Ok(OpCode::OpGreater),
+            18 => Ok(OpCode::OpLess),
+            19 => Ok(OpCode::OpAdd),
+            20 => Ok(OpCode::OpSubtract),
+            21 => Ok(OpCode::OpMultiply),
+            22 => Ok(OpCode::OpDivide),
+            23 => Ok(OpCode::OpNot),
+            24 => Ok(OpCode::OpNegate),
+            25 => Ok(OpCode::OpPrint),
+            26 => Ok(OpCode::OpJump),
+            27 => Ok(OpCode::OpJumpIfFalse),
+            28 => Ok(OpCode::OpLoop),
+            29 => Ok(OpCode::OpCall),
+            30 => Ok(OpCode::OpInvoke),
+            31 => Ok(OpCode::OpSuperInvoke),
+            32 => Ok(OpCode::OpClosure),
+            33 => Ok(OpCode::OpCloseUpvalue),
+            34 => Ok(OpCode::OpReturn),
+            35 => Ok(OpCode::OpClass),
+            36 => Ok(OpCode::OpInherit),
+            37 => Ok(OpCode::OpMethod),
+            38 => Ok(OpCode::OpGetGlobalLong),
+            39 => Ok(OpCode::OpDefineGlobalLong),
+            40 => Ok(OpCode::OpSetGlobalLong),
+            41 => Ok(OpCode::OpGetPropertyLong),
+            42 => Ok(OpCode::OpSetPropertyLong),
+            43 => Ok(OpCode::OpGetSuperLong),
+            44 => Ok(OpCode::OpInvokeLong),
+            45 => Ok(OpCode::OpSuperInvokeLong),
+            46 => Ok(OpCode::OpClosureLong),
+            47 => Ok(OpCode::OpClassLong),
+            48 => Ok(OpCode::OpMethodLong),
             _ => Err(Error::InvalidOpCode(value)),
         }
     }
 }
 
+/// Instructions that take a constant index have a long form with a 24-bit
+/// operand, for chunks with more than 256 constants.
+impl OpCode {
+    /// The long form of an instruction that takes a constant index.
+    pub fn long(self) -> Option<OpCode> {
+        use OpCode::*;
+
+        match self {
+            OpConstant => Some(OpConstantLong),
+            OpGetGlobal => Some(OpGetGlobalLong),
+            OpDefineGlobal => Some(OpDefineGlobalLong),
+            OpSetGlobal => Some(OpSetGlobalLong),
+            OpGetProperty => Some(OpGetPropertyLong),
+            OpSetProperty => Some(OpSetPropertyLong),
+            OpGetSuper => Some(OpGetSuperLong),
+            OpInvoke => Some(OpInvokeLong),
+            OpSuperInvoke => Some(OpSuperInvokeLong),
+            OpClosure => Some(OpClosureLong),
+            OpClass => Some(OpClassLong),
+            OpMethod => Some(OpMethodLong),
+            _ => None,
+        }
+    }
+
+    /// The short form of a long instruction, or the instruction itself.
+    pub fn short(self) -> OpCode {
+        use OpCode::*;
+
+        match self {
+            OpConstantLong => OpConstant,
+            OpGetGlobalLong => OpGetGlobal,
+            OpDefineGlobalLong => OpDefineGlobal,
+            OpSetGlobalLong => OpSetGlobal,
+            OpGetPropertyLong => OpGetProperty,
+            OpSetPropertyLong => OpSetProperty,
+            OpGetSuperLong => OpGetSuper,
+            OpInvokeLong => OpInvoke,
+            OpSuperInvokeLong => OpSuperInvoke,
+            OpClosureLong => OpClosure,
+            OpClassLong => OpClass,
+            OpMethodLong => OpMethod,
+            op => op,
+        }
+    }
+
+    pub fn is_long(self) -> bool {
+        self.short() != self
+    }
+}
+
 // create a struct to represent a chunk of bytecode
 pub struct Chunk {
-    pub ip: usize,
     // store the bytecode in a vector
     pub code: Vec<u8>,
     pub constants: Vec<Value>,
-    pub lines: Vec<usize>,
+    /// Run-length encoded line numbers. See `line_at`.
+    pub lines: Vec<LineRun>,
+    /// Run-length encoded source spans. See `span_at`.
+    pub spans: Vec<SpanRun>,
+    /// The source the chunk was compiled from, or `None` if it came from
+    /// somewhere else, like a `.loxc` file.
+    pub source: Option<SourceId>,
+}
+
+/// A run of consecutive bytes that all came from the same line.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct LineRun {
+    /// The offset of the first byte in the run.
+    pub start: usize,
+    pub line: usize,
+}
+
+/// A run of consecutive bytes that were all compiled from the same span of
+/// source, which is usually a whole instruction.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SpanRun {
+    /// The offset of the first byte in the run.
+    pub start: usize,
+    pub span: Range<usize>,
 }
 
 /// Create a new chunk
 impl Chunk {
     pub fn new() -> Chunk {
         Chunk {
-            ip: 0,
             code: Vec::new(),
             constants: Vec::new(),
             lines: Vec::new(),
+            spans: Vec::new(),
+            source: None,
         }
     }
 
     /// Write a byte to the chunk
-    pub fn write(&mut self, op_code: u8, line_no: usize) {
+    pub fn write(&mut self, op_code: u8, line_no: usize, span: Range<usize>) {
+        if self.lines.last().is_none_or(|run| run.line != line_no) {
+            self.lines.push(LineRun {
+                start: self.code.len(),
+                line: line_no,
+            });
+        }
+        if self.spans.last().is_none_or(|run| run.span != span) {
+            self.spans.push(SpanRun {
+                start: self.code.len(),
+                span,
+            });
+        }
         self.code.push(op_code);
-        self.lines.push(line_no);
     }
 
-    pub fn add_constant(&mut self, value: f64) -> usize {
+    /// The source line of the byte at `offset`.
+    pub fn line_at(&self, offset: usize) -> usize {
+        // Find the last run that starts at or before the offset.
+        let index = self.lines.partition_point(|run| run.start <= offset);
+        index
+            .checked_sub(1)
+            .map(|index| self.lines[index].line)
+            .unwrap_or_default()
+    }
+
+    /// The source span the byte at `offset` was compiled from, if there is
+    /// one.
+    pub fn span_at(&self, offset: usize) -> Option<Range<usize>> {
+        let index = self.spans.partition_point(|run| run.start <= offset);
+        index
+            .checked_sub(1)
+            .map(|index| self.spans[index].span.clone())
+    }
+
+    pub fn add_constant(&mut self, value: Value) -> usize {
         self.constants.push(value);
         self.constants.len() - 1
     }
@@ -65,23 +255,6 @@ impl Chunk {
     pub fn get(&self, index: usize) -> u8 {
         self.code[index]
     }
-
-    #[inline]
-    pub fn read_op_code(&mut self) -> u8 {
-        self.ip += 1;
-        self.code[self.ip - 1]
-    }
-
-    #[inline]
-    pub fn read_constant(&mut self) -> f64 {
-        let constant = self.read_op_code();
-        self.constants[constant as usize]
-    }
-
-    #[inline]
-    pub fn at_end(&self) -> bool {
-        self.ip >= self.code.len()
-    }
 }
 
 impl Default for Chunk {
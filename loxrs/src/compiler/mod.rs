@@ -1,88 +1,1171 @@
-use crate::chunk::Chunk;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use log;
+
+use crate::chunk::{Chunk, OpCode};
+use crate::debug::dissassemble_chunk;
+use crate::diagnostic::{Diagnostic, SourceId};
 use crate::error::{Error, Result};
+use crate::object::{Obj, ObjFunction, ObjRef};
 use crate::scanner::{Scanner, Token, TokenType};
+use crate::value::Value;
+use crate::verifier::NESTING_MAX;
+use crate::vm::VM;
+
+#[derive(Debug, PartialEq, PartialOrd, Copy, Clone)]
+enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // ! -
+    Call,       // . ()
+    Primary,
+}
+
+impl Precedence {
+    fn next(self) -> Precedence {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call => Precedence::Primary,
+            Precedence::Primary => Precedence::Primary,
+        }
+    }
+}
+
+type ParseFn<'a> = fn(&mut Compiler<'a>, bool) -> Result<()>;
+
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
+    precedence: Precedence,
+}
+
+impl<'a> ParseRule<'a> {
+    fn new(
+        prefix: Option<ParseFn<'a>>,
+        infix: Option<ParseFn<'a>>,
+        precedence: Precedence,
+    ) -> Self {
+        Self {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
+/// Look up the prefix and infix parsers for a token type, along with the
+/// precedence of the token when it's used as an infix operator.
+fn get_rule<'a>(ty: TokenType) -> ParseRule<'a> {
+    use TokenType::*;
+
+    match ty {
+        LeftParen => ParseRule::new(
+            Some(Compiler::grouping),
+            Some(Compiler::call),
+            Precedence::Call,
+        ),
+        Minus => ParseRule::new(
+            Some(Compiler::unary),
+            Some(Compiler::binary),
+            Precedence::Term,
+        ),
+        Plus => ParseRule::new(None, Some(Compiler::binary), Precedence::Term),
+        Slash => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+        Star => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+        Bang => ParseRule::new(Some(Compiler::unary), None, Precedence::None),
+        BangEqual => ParseRule::new(None, Some(Compiler::binary), Precedence::Equality),
+        EqualEqual => ParseRule::new(None, Some(Compiler::binary), Precedence::Equality),
+        Greater => ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison),
+        GreaterEqual => ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison),
+        Less => ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison),
+        LessEqual => ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison),
+        Dot => ParseRule::new(None, Some(Compiler::dot), Precedence::Call),
+        Identifier => ParseRule::new(Some(Compiler::variable), None, Precedence::None),
+        String => ParseRule::new(Some(Compiler::string), None, Precedence::None),
+        Number => ParseRule::new(Some(Compiler::number), None, Precedence::None),
+        And => ParseRule::new(None, Some(Compiler::and), Precedence::And),
+        Or => ParseRule::new(None, Some(Compiler::or), Precedence::Or),
+        False => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
+        Nil => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
+        True => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
+        Super => ParseRule::new(Some(Compiler::super_), None, Precedence::None),
+        This => ParseRule::new(Some(Compiler::this), None, Precedence::None),
+        _ => ParseRule::new(None, None, Precedence::None),
+    }
+}
+
+const LOCALS_MAX: usize = u8::MAX as usize + 1;
 
-struct Compiler {
-    chunk: Option<Chunk>,
+const UPVALUES_MAX: usize = u8::MAX as usize + 1;
+
+/// The long forms of instructions have a 24-bit constant index.
+const CONSTANTS_LONG_MAX: usize = 1 << 24;
+
+struct Local {
+    name: String,
+    /// The scope depth, or `None` while the variable's initializer is being
+    /// compiled.
+    depth: Option<usize>,
+    /// Whether a closure captures this local, in which case it has to be
+    /// hoisted onto the heap when it goes out of scope.
+    is_captured: bool,
+}
+
+/// A variable captured from an enclosing function, either one of its locals
+/// or one of its own upvalues.
+#[derive(Debug, PartialEq, Copy, Clone)]
+struct Upvalue {
+    index: u8,
+    is_local: bool,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+/// The state for the function currently being compiled. These nest, one for
+/// each function declaration we're inside of.
+struct FunctionState {
+    function: ObjFunction,
+    ty: FunctionType,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+    /// Where each name is in the chunk's constants, so mentioning a name
+    /// again reuses its constant.
+    identifiers: HashMap<ObjRef, usize>,
+}
+
+impl FunctionState {
+    fn new(ty: FunctionType, name: Option<ObjRef>) -> Self {
+        let mut locals = Vec::with_capacity(LOCALS_MAX);
+        // The VM uses slot zero for the function being called, or for the
+        // receiver in methods.
+        let slot_zero = match ty {
+            FunctionType::Initializer | FunctionType::Method => "this",
+            FunctionType::Function | FunctionType::Script => "",
+        };
+        locals.push(Local {
+            name: slot_zero.to_string(),
+            depth: Some(0),
+            is_captured: false,
+        });
+
+        Self {
+            function: ObjFunction::new(name),
+            ty,
+            locals,
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            identifiers: HashMap::new(),
+        }
+    }
+}
+
+/// The state for the class currently being compiled.
+struct ClassState {
+    has_superclass: bool,
+}
+
+struct Compiler<'a> {
+    scanner: Scanner,
+    /// Stamped on every chunk, so runtime errors know what their spans are
+    /// spans of.
+    source_id: SourceId,
+    vm: &'a mut VM,
+    states: Vec<FunctionState>,
+    classes: Vec<ClassState>,
     current: Option<Token>,
     previous: Option<Token>,
-    has_errors: bool,
+    diagnostics: Vec<Diagnostic>,
+    panic_mode: bool,
 }
 
-pub fn compile<S: AsRef<str>>(source: S) -> Result<Chunk> {
+/// Compile the source into the top-level script function, returning a
+/// reference to it on the heap. If there are errors, they're all returned
+/// together in `Error::CompileError`.
+pub fn compile<S: AsRef<str>>(source: S, vm: &mut VM) -> Result<ObjRef> {
     let source = source.as_ref().to_string();
-    let mut scanner = Scanner::new(source);
-    let mut compiler = Compiler::new();
+    let scanner = Scanner::new(source);
+    let mut compiler = Compiler::new(scanner, vm);
 
-    compiler.advance(&mut scanner);
-    compiler.expression()?;
-    compiler.consume(TokenType::EOF, &mut scanner, "Expect end of expression");
+    compiler.advance();
+    while !compiler.match_token(TokenType::EOF) {
+        compiler.declaration()?;
+    }
+    let (function, _) = compiler.end_compiler()?;
 
-    if compiler.has_errors {
-        Err(Error::CompileError)
+    if !compiler.diagnostics.is_empty() {
+        Err(Error::CompileError(compiler.diagnostics))
     } else {
-        compiler.chunk.take().ok_or(Error::MissingChunkError)
+        Ok(function)
     }
 }
 
-impl Compiler {
-    fn new() -> Self {
+impl<'a> Compiler<'a> {
+    fn new(scanner: Scanner, vm: &'a mut VM) -> Self {
         Self {
-            chunk: None,
+            source_id: SourceId::of(&scanner.input),
+            scanner,
+            vm,
+            states: vec![FunctionState::new(FunctionType::Script, None)],
+            classes: Vec::new(),
             current: None,
             previous: None,
-            has_errors: false,
+            diagnostics: Vec::new(),
+            panic_mode: false,
         }
     }
 
-    fn advance(&mut self, scanner: &mut Scanner) {
+    fn advance(&mut self) {
         self.previous = self.current.take();
 
-        let (current, errors) = scanner.scan_to_next();
-        self.has_errors = !errors.is_empty();
-        if errors.first().is_some() {
-            let text = current
-                .as_ref()
-                .map(|t| t.text(&scanner.input))
-                .unwrap_or("Missing token");
-            self.error_at_current(scanner, text);
+        let (current, errors) = self.scanner.scan_to_next();
+        self.current = current;
+        for diagnostic in errors {
+            self.report_error(diagnostic);
         }
+    }
 
-        self.current = current;
+    fn declaration(&mut self) -> Result<()> {
+        if self.match_token(TokenType::Class) {
+            self.class_declaration()?;
+        } else if self.match_token(TokenType::Fun) {
+            self.fun_declaration()?;
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration()?;
+        } else {
+            self.statement()?;
+        }
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+        Ok(())
+    }
+
+    /// Skip tokens until we're probably at the start of a new statement, so
+    /// one mistake doesn't cause a cascade of errors.
+    fn synchronize(&mut self) {
+        use TokenType::*;
+
+        self.panic_mode = false;
+        while self.current_type() != EOF {
+            if self.previous_type() == Semicolon {
+                return;
+            }
+            match self.current_type() {
+                Class | Fun | Var | For | If | While | Print | Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn class_declaration(&mut self) -> Result<()> {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous_text().to_string();
+        let name_constant = self.identifier_constant(&class_name)?;
+        self.declare_variable();
+
+        self.emit_indexed(OpCode::OpClass, name_constant)?;
+        self.define_variable(name_constant)?;
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+
+        if self.match_token(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false)?;
+
+            if class_name == self.previous_text() {
+                self.error("A class can't inherit from itself.");
+            }
+
+            // Keep the superclass in a local named "super" so that methods
+            // capture it as an upvalue.
+            self.begin_scope();
+            self.add_local("super".to_string());
+            self.define_variable(0)?;
+
+            self.named_variable(&class_name, false)?;
+            self.emit_byte(OpCode::OpInherit as u8)?;
+            if let Some(class) = self.classes.last_mut() {
+                class.has_superclass = true;
+            }
+        }
+
+        // Load the class back onto the stack so methods can be bound to it.
+        self.named_variable(&class_name, false)?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            self.method()?;
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::OpPop as u8)?;
+
+        if self.classes.pop().is_some_and(|class| class.has_superclass) {
+            self.end_scope()?;
+        }
+        Ok(())
+    }
+
+    fn method(&mut self) -> Result<()> {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let constant = self.previous_identifier_constant()?;
+
+        let ty = if self.previous_text() == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(ty)?;
+        self.emit_indexed(OpCode::OpMethod, constant)
+    }
+
+    fn fun_declaration(&mut self) -> Result<()> {
+        let global = self.parse_variable("Expect function name.")?;
+        self.mark_initialized();
+        self.function(FunctionType::Function)?;
+        self.define_variable(global)
+    }
+
+    fn function(&mut self, ty: FunctionType) -> Result<()> {
+        let name = self.previous_text().to_string();
+        let name = self.take_string(name);
+        self.states.push(FunctionState::new(ty, Some(name)));
+        // The script itself is the first state.
+        if self.states.len() - 1 > NESTING_MAX {
+            self.error("Too many nested functions.");
+        }
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.state_mut().function.arity += 1;
+                if self.state().function.arity > u8::MAX as usize {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.")?;
+                self.define_variable(constant)?;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block()?;
+
+        let (function, upvalues) = self.end_compiler()?;
+        let constant = self.make_constant(Value::Obj(function))?;
+        self.emit_indexed(OpCode::OpClosure, constant)?;
+
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index)?;
+        }
+        Ok(())
+    }
+
+    fn var_declaration(&mut self) -> Result<()> {
+        let global = self.parse_variable("Expect variable name.")?;
+
+        if self.match_token(TokenType::Equal) {
+            self.expression()?;
+        } else {
+            self.emit_byte(OpCode::OpNil as u8)?;
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        self.define_variable(global)
+    }
+
+    fn statement(&mut self) -> Result<()> {
+        if self.match_token(TokenType::Print) {
+            self.print_statement()
+        } else if self.match_token(TokenType::For) {
+            self.for_statement()
+        } else if self.match_token(TokenType::If) {
+            self.if_statement()
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement()
+        } else if self.match_token(TokenType::While) {
+            self.while_statement()
+        } else if self.match_token(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block()?;
+            self.end_scope()
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    fn block(&mut self) -> Result<()> {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            self.declaration()?;
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+        Ok(())
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) -> Result<()> {
+        self.state_mut().scope_depth -= 1;
+
+        loop {
+            let state = self.state();
+            let is_captured = match state.locals.last() {
+                Some(local) if local.depth.is_some_and(|depth| depth > state.scope_depth) => {
+                    local.is_captured
+                }
+                _ => break,
+            };
+            if is_captured {
+                self.emit_byte(OpCode::OpCloseUpvalue as u8)?;
+            } else {
+                self.emit_byte(OpCode::OpPop as u8)?;
+            }
+            self.state_mut().locals.pop();
+        }
+
+        Ok(())
+    }
+
+    fn print_statement(&mut self) -> Result<()> {
+        self.expression()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit_byte(OpCode::OpPrint as u8)
+    }
+
+    fn for_statement(&mut self) -> Result<()> {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.match_token(TokenType::Semicolon) {
+            // No initializer.
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration()?;
+        } else {
+            self.expression_statement()?;
+        }
+
+        let mut loop_start = self.current_chunk()?.code.len();
+        let mut exit_jump = None;
+        if !self.match_token(TokenType::Semicolon) {
+            self.expression()?;
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            exit_jump = Some(self.emit_jump(OpCode::OpJumpIfFalse)?);
+            self.emit_byte(OpCode::OpPop as u8)?;
+        }
+
+        if !self.match_token(TokenType::RightParen) {
+            let body_jump = self.emit_jump(OpCode::OpJump)?;
+            let increment_start = self.current_chunk()?.code.len();
+            self.expression()?;
+            self.emit_byte(OpCode::OpPop as u8)?;
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start)?;
+            loop_start = increment_start;
+            self.patch_jump(body_jump)?;
+        }
+
+        self.statement()?;
+        self.emit_loop(loop_start)?;
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump)?;
+            self.emit_byte(OpCode::OpPop as u8)?;
+        }
+
+        self.end_scope()
+    }
+
+    fn if_statement(&mut self) -> Result<()> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::OpJumpIfFalse)?;
+        self.emit_byte(OpCode::OpPop as u8)?;
+        self.statement()?;
+
+        let else_jump = self.emit_jump(OpCode::OpJump)?;
+
+        self.patch_jump(then_jump)?;
+        self.emit_byte(OpCode::OpPop as u8)?;
+
+        if self.match_token(TokenType::Else) {
+            self.statement()?;
+        }
+        self.patch_jump(else_jump)
+    }
+
+    fn return_statement(&mut self) -> Result<()> {
+        if self.state().ty == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_return()
+        } else {
+            if self.state().ty == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+
+            self.expression()?;
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_byte(OpCode::OpReturn as u8)
+        }
+    }
+
+    fn while_statement(&mut self) -> Result<()> {
+        let loop_start = self.current_chunk()?.code.len();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::OpJumpIfFalse)?;
+        self.emit_byte(OpCode::OpPop as u8)?;
+        self.statement()?;
+        self.emit_loop(loop_start)?;
+
+        self.patch_jump(exit_jump)?;
+        self.emit_byte(OpCode::OpPop as u8)
+    }
+
+    fn expression_statement(&mut self) -> Result<()> {
+        self.expression()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit_byte(OpCode::OpPop as u8)
     }
 
     fn expression(&mut self) -> Result<()> {
-        todo!()
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) -> Result<()> {
+        self.advance();
+        let prefix = match get_rule(self.previous_type()).prefix {
+            Some(prefix) => prefix,
+            None => {
+                self.error("Expect expression.");
+                return Ok(());
+            }
+        };
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix(self, can_assign)?;
+
+        while precedence <= get_rule(self.current_type()).precedence {
+            self.advance();
+            if let Some(infix) = get_rule(self.previous_type()).infix {
+                infix(self, can_assign)?;
+            }
+        }
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.error("Invalid assignment target.");
+        }
+
+        Ok(())
+    }
+
+    fn and(&mut self, _can_assign: bool) -> Result<()> {
+        let end_jump = self.emit_jump(OpCode::OpJumpIfFalse)?;
+
+        self.emit_byte(OpCode::OpPop as u8)?;
+        self.parse_precedence(Precedence::And)?;
+
+        self.patch_jump(end_jump)
+    }
+
+    fn or(&mut self, _can_assign: bool) -> Result<()> {
+        let else_jump = self.emit_jump(OpCode::OpJumpIfFalse)?;
+        let end_jump = self.emit_jump(OpCode::OpJump)?;
+
+        self.patch_jump(else_jump)?;
+        self.emit_byte(OpCode::OpPop as u8)?;
+
+        self.parse_precedence(Precedence::Or)?;
+        self.patch_jump(end_jump)
+    }
+
+    fn call(&mut self, _can_assign: bool) -> Result<()> {
+        let arg_count = self.argument_list()?;
+        self.emit_bytes(OpCode::OpCall as u8, arg_count)
+    }
+
+    fn argument_list(&mut self) -> Result<u8> {
+        let mut arg_count: usize = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression()?;
+                if arg_count == u8::MAX as usize {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        Ok(arg_count.min(u8::MAX as usize) as u8)
+    }
+
+    fn dot(&mut self, can_assign: bool) -> Result<()> {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.previous_identifier_constant()?;
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression()?;
+            self.emit_indexed(OpCode::OpSetProperty, name)
+        } else if self.match_token(TokenType::LeftParen) {
+            let arg_count = self.argument_list()?;
+            self.emit_indexed(OpCode::OpInvoke, name)?;
+            self.emit_byte(arg_count)
+        } else {
+            self.emit_indexed(OpCode::OpGetProperty, name)
+        }
     }
 
-    fn consume(&mut self, ty: TokenType, scanner: &mut Scanner, message: &str) {
-        if self.current.as_ref().map(|t| t.ty) == Some(ty) {
-            self.advance(scanner);
+    fn super_(&mut self, _can_assign: bool) -> Result<()> {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            Some(_) => {}
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.previous_identifier_constant()?;
+
+        self.named_variable("this", false)?;
+        if self.match_token(TokenType::LeftParen) {
+            let arg_count = self.argument_list()?;
+            self.named_variable("super", false)?;
+            self.emit_indexed(OpCode::OpSuperInvoke, name)?;
+            self.emit_byte(arg_count)
+        } else {
+            self.named_variable("super", false)?;
+            self.emit_indexed(OpCode::OpGetSuper, name)
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) -> Result<()> {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return Ok(());
+        }
+
+        self.variable(false)
+    }
+
+    fn parse_variable(&mut self, message: &str) -> Result<usize> {
+        self.consume(TokenType::Identifier, message);
+
+        self.declare_variable();
+        if self.state().scope_depth > 0 {
+            return Ok(0);
+        }
+
+        self.previous_identifier_constant()
+    }
+
+    fn previous_identifier_constant(&mut self) -> Result<usize> {
+        let name = self.previous_text().to_string();
+        self.identifier_constant(&name)
+    }
+
+    /// Reuse the constant if the name's already in the chunk, so a script
+    /// that mentions a name many times only stores it once.
+    fn identifier_constant(&mut self, name: &str) -> Result<usize> {
+        let reference = self.take_string(name.to_string());
+        if let Some(&constant) = self.state().identifiers.get(&reference) {
+            return Ok(constant);
+        }
+        let constant = self.make_constant(Value::Obj(reference))?;
+        self.state_mut().identifiers.insert(reference, constant);
+        Ok(constant)
+    }
+
+    fn declare_variable(&mut self) {
+        let state = self.state();
+        if state.scope_depth == 0 {
+            return;
+        }
+
+        let name = self.previous_text();
+        let already_declared = state
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= state.scope_depth))
+            .any(|local| local.name == name);
+        let name = name.to_string();
+        if already_declared {
+            self.error("Already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: String) {
+        if self.state().locals.len() == LOCALS_MAX {
+            self.error("Too many local variables in function.");
+            return;
+        }
+
+        self.state_mut().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+        if state.scope_depth == 0 {
             return;
         }
-        self.error_at_current(scanner, message)
+        let scope_depth = state.scope_depth;
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = Some(scope_depth);
+        }
     }
 
-    fn error_at_current(&self, scanner: &Scanner, message: &str) {
-        self.error_at(&self.previous, scanner, message);
+    fn define_variable(&mut self, global: usize) -> Result<()> {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return Ok(());
+        }
+
+        self.emit_indexed(OpCode::OpDefineGlobal, global)
     }
 
-    fn error_at(&self, previous: &Option<Token>, scanner: &Scanner, message: &str) {
-        let previous = previous.as_ref();
-        let line = previous.map(|token| token.line).unwrap_or_default();
-        eprint!("[line {}] Error", line);
+    fn resolve_local(&mut self, state_index: usize, name: &str) -> Option<u8> {
+        let (slot, local) = self.states[state_index]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name)?;
+
+        if local.depth.is_none() {
+            self.error("Can't read local variable in its own initializer.");
+        }
+        Some(slot as u8)
+    }
 
-        match previous.map(|token| token.ty) {
-            Some(TokenType::EOF) => eprint!(" at end"),
-            Some(TokenType::Error) => {}
-            Some(_) => {
-                // TODO: seems like this is getting called twice.
-                let token_text = previous.map(|t| t.text(&scanner.input));
-                eprint!(" at {:?}", token_text.unwrap_or_default());
+    /// Look for the variable in the functions enclosing the one at
+    /// `state_index`, threading an upvalue through each function in between.
+    fn resolve_upvalue(&mut self, state_index: usize, name: &str) -> Option<u8> {
+        if state_index == 0 {
+            return None;
+        }
+
+        if let Some(local) = self.resolve_local(state_index - 1, name) {
+            self.states[state_index - 1].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(state_index, local, true));
+        }
+
+        let upvalue = self.resolve_upvalue(state_index - 1, name)?;
+        Some(self.add_upvalue(state_index, upvalue, false))
+    }
+
+    fn add_upvalue(&mut self, state_index: usize, index: u8, is_local: bool) -> u8 {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &self.states[state_index].upvalues;
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return existing as u8;
+        }
+
+        if upvalues.len() == UPVALUES_MAX {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+
+        let upvalues = &mut self.states[state_index].upvalues;
+        upvalues.push(upvalue);
+        (upvalues.len() - 1) as u8
+    }
+
+    fn variable(&mut self, can_assign: bool) -> Result<()> {
+        let name = self.previous_text().to_string();
+        self.named_variable(&name, can_assign)
+    }
+
+    fn named_variable(&mut self, name: &str, can_assign: bool) -> Result<()> {
+        let current = self.states.len() - 1;
+
+        let (get_op, set_op, arg) = if let Some(slot) = self.resolve_local(current, name) {
+            (OpCode::OpGetLocal, OpCode::OpSetLocal, slot as usize)
+        } else if let Some(index) = self.resolve_upvalue(current, name) {
+            (OpCode::OpGetUpvalue, OpCode::OpSetUpvalue, index as usize)
+        } else {
+            (
+                OpCode::OpGetGlobal,
+                OpCode::OpSetGlobal,
+                self.identifier_constant(name)?,
+            )
+        };
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression()?;
+            self.emit_indexed(set_op, arg)
+        } else {
+            self.emit_indexed(get_op, arg)
+        }
+    }
+
+    fn number(&mut self, _can_assign: bool) -> Result<()> {
+        let value = self.previous_text().parse::<f64>();
+        match value {
+            Ok(value) => self.emit_constant(Value::Number(value)),
+            Err(_) => {
+                self.error("Invalid number.");
+                Ok(())
+            }
+        }
+    }
+
+    fn string(&mut self, _can_assign: bool) -> Result<()> {
+        let text = self.previous_text();
+        let text = &text[1..text.len() - 1];
+        match unescape(text) {
+            Some(chars) => {
+                let reference = self.take_string(chars);
+                self.emit_constant(Value::Obj(reference))
             }
-            None => {}
+            None => {
+                self.error("Invalid escape sequence.");
+                Ok(())
+            }
+        }
+    }
+
+    fn literal(&mut self, _can_assign: bool) -> Result<()> {
+        match self.previous_type() {
+            TokenType::False => self.emit_byte(OpCode::OpFalse as u8),
+            TokenType::Nil => self.emit_byte(OpCode::OpNil as u8),
+            TokenType::True => self.emit_byte(OpCode::OpTrue as u8),
+            _ => Ok(()),
+        }
+    }
+
+    fn grouping(&mut self, _can_assign: bool) -> Result<()> {
+        self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+        Ok(())
+    }
+
+    fn unary(&mut self, _can_assign: bool) -> Result<()> {
+        let operator = self.previous;
+        let operator_type = self.previous_type();
+
+        self.parse_precedence(Precedence::Unary)?;
+
+        match operator_type {
+            TokenType::Bang => self.emit_byte_at(OpCode::OpNot as u8, operator),
+            TokenType::Minus => self.emit_byte_at(OpCode::OpNegate as u8, operator),
+            _ => Ok(()),
+        }
+    }
+
+    fn binary(&mut self, _can_assign: bool) -> Result<()> {
+        let operator = self.previous;
+        let operator_type = self.previous_type();
+        let rule = get_rule(operator_type);
+        self.parse_precedence(rule.precedence.next())?;
+
+        // Attribute the instructions to the operator, so a type error points
+        // at it rather than at the end of the right operand.
+        let ops: &[OpCode] = match operator_type {
+            TokenType::BangEqual => &[OpCode::OpEqual, OpCode::OpNot],
+            TokenType::EqualEqual => &[OpCode::OpEqual],
+            TokenType::Greater => &[OpCode::OpGreater],
+            TokenType::GreaterEqual => &[OpCode::OpLess, OpCode::OpNot],
+            TokenType::Less => &[OpCode::OpLess],
+            TokenType::LessEqual => &[OpCode::OpGreater, OpCode::OpNot],
+            TokenType::Plus => &[OpCode::OpAdd],
+            TokenType::Minus => &[OpCode::OpSubtract],
+            TokenType::Star => &[OpCode::OpMultiply],
+            TokenType::Slash => &[OpCode::OpDivide],
+            _ => &[],
+        };
+        for &op in ops {
+            self.emit_byte_at(op as u8, operator)?;
         }
+        Ok(())
+    }
 
-        eprintln!(": {}", message);
+    fn check(&self, ty: TokenType) -> bool {
+        self.current_type() == ty
     }
+
+    fn match_token(&mut self, ty: TokenType) -> bool {
+        if !self.check(ty) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn consume(&mut self, ty: TokenType, message: &str) {
+        if self.check(ty) {
+            self.advance();
+            return;
+        }
+        self.error_at_current(message)
+    }
+
+    fn current_chunk(&mut self) -> Result<&mut Chunk> {
+        self.states
+            .last_mut()
+            .and_then(|state| Rc::get_mut(&mut state.function.chunk))
+            .ok_or(Error::MissingChunkError)
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<()> {
+        let previous = self.previous;
+        self.emit_byte_at(byte, previous)
+    }
+
+    /// Emit a byte attributed to `token` rather than the previous token.
+    fn emit_byte_at(&mut self, byte: u8, token: Option<Token>) -> Result<()> {
+        let line = token.map(|t| t.line).unwrap_or_default();
+        let span = token.map(|t| t.span()).unwrap_or_default();
+        self.current_chunk()?.write(byte, line, span);
+        Ok(())
+    }
+
+    fn emit_bytes(&mut self, byte1: u8, byte2: u8) -> Result<()> {
+        self.emit_byte(byte1)?;
+        self.emit_byte(byte2)
+    }
+
+    /// Emit a jump with a placeholder operand, returning the operand's
+    /// offset so it can be filled in by `patch_jump`.
+    fn emit_jump(&mut self, instruction: OpCode) -> Result<usize> {
+        self.emit_byte(instruction as u8)?;
+        self.emit_bytes(0xff, 0xff)?;
+        Ok(self.current_chunk()?.code.len() - 2)
+    }
+
+    fn patch_jump(&mut self, offset: usize) -> Result<()> {
+        // -2 to adjust for the bytecode for the jump offset itself.
+        let jump = self.current_chunk()?.code.len() - offset - 2;
+
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+
+        let [high, low] = (jump as u16).to_be_bytes();
+        let chunk = self.current_chunk()?;
+        chunk.code[offset] = high;
+        chunk.code[offset + 1] = low;
+        Ok(())
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) -> Result<()> {
+        self.emit_byte(OpCode::OpLoop as u8)?;
+
+        let offset = self.current_chunk()?.code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+
+        let [high, low] = (offset as u16).to_be_bytes();
+        self.emit_bytes(high, low)
+    }
+
+    fn emit_constant(&mut self, value: Value) -> Result<()> {
+        let constant = self.make_constant(value)?;
+        self.emit_indexed(OpCode::OpConstant, constant)
+    }
+
+    /// Emit an instruction with a one-byte operand. Instructions that take a
+    /// constant index switch to their long form, with a three-byte operand,
+    /// once the chunk has outgrown a one-byte index.
+    fn emit_indexed(&mut self, op: OpCode, index: usize) -> Result<()> {
+        match op.long() {
+            Some(long) if index > u8::MAX as usize => {
+                let [_, high, middle, low] = (index as u32).to_be_bytes();
+                self.emit_bytes(long as u8, high)?;
+                self.emit_bytes(middle, low)
+            }
+            _ => self.emit_bytes(op as u8, index as u8),
+        }
+    }
+
+    fn make_constant(&mut self, value: Value) -> Result<usize> {
+        let constant = self.current_chunk()?.add_constant(value);
+        if constant >= CONSTANTS_LONG_MAX {
+            self.error("Too many constants in one chunk.");
+            return Ok(0);
+        }
+        Ok(constant)
+    }
+
+    fn emit_return(&mut self) -> Result<()> {
+        if self.state().ty == FunctionType::Initializer {
+            // Initializers always return the instance in slot zero.
+            self.emit_bytes(OpCode::OpGetLocal as u8, 0)?;
+        } else {
+            self.emit_byte(OpCode::OpNil as u8)?;
+        }
+        self.emit_byte(OpCode::OpReturn as u8)
+    }
+
+    /// Finish the function currently being compiled and move it onto the
+    /// heap, returning it with the upvalues its closure needs to capture.
+    fn end_compiler(&mut self) -> Result<(ObjRef, Vec<Upvalue>)> {
+        self.emit_return()?;
+        self.current_chunk()?.source = Some(self.source_id);
+        // Collect while the function's constants are still rooted.
+        self.maybe_collect_garbage();
+        let state = self.states.pop().ok_or(Error::MissingChunkError)?;
+        let mut function = state.function;
+        function.upvalue_count = state.upvalues.len();
+
+        if log::max_level() >= log::Level::Debug && self.diagnostics.is_empty() {
+            let name = function
+                .name
+                .and_then(|name| self.vm.heap.as_string(name))
+                .map(|name| &*name.chars)
+                .unwrap_or("<script>");
+            dissassemble_chunk(&function.chunk, name, &self.vm.heap);
+        }
+
+        let function = self.vm.heap.alloc(Obj::Function(function));
+        Ok((function, state.upvalues))
+    }
+
+    fn take_string(&mut self, chars: String) -> ObjRef {
+        self.maybe_collect_garbage();
+        self.vm.heap.take_string(chars)
+    }
+
+    /// The functions being compiled aren't on the heap yet, so mark what they
+    /// refer to before letting the VM collect.
+    fn maybe_collect_garbage(&mut self) {
+        if !self.vm.heap.should_collect() {
+            return;
+        }
+
+        for state in self.states.iter() {
+            if let Some(name) = state.function.name {
+                self.vm.heap.mark_object(name);
+            }
+            for &constant in state.function.chunk.constants.iter() {
+                self.vm.heap.mark_value(constant);
+            }
+        }
+        self.vm.collect_garbage();
+    }
+
+    fn state(&self) -> &FunctionState {
+        self.states.last().expect("no function is being compiled")
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState {
+        self.states
+            .last_mut()
+            .expect("no function is being compiled")
+    }
+
+    fn current_type(&self) -> TokenType {
+        self.current.map(|t| t.ty).unwrap_or(TokenType::EOF)
+    }
+
+    fn previous_type(&self) -> TokenType {
+        self.previous.map(|t| t.ty).unwrap_or(TokenType::EOF)
+    }
+
+    fn previous_text(&self) -> &str {
+        self.previous
+            .as_ref()
+            .map(|t| t.text(&self.scanner.input))
+            .unwrap_or_default()
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        let current = self.current;
+        self.error_at(current, message);
+    }
+
+    fn error(&mut self, message: &str) {
+        let previous = self.previous;
+        self.error_at(previous, message);
+    }
+
+    fn error_at(&mut self, token: Option<Token>, message: &str) {
+        let diagnostic = match token {
+            Some(token) => Diagnostic::at_token(&self.scanner.input, &token, message),
+            None => Diagnostic::at_span(0..0, 0, 0, message),
+        };
+        self.report_error(diagnostic);
+    }
+
+    /// Record an error, unless we're still recovering from an earlier one.
+    fn report_error(&mut self, diagnostic: Diagnostic) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        self.diagnostics.push(diagnostic);
+    }
+}
+
+/// Translate the escape sequences in a string literal's contents.
+fn unescape(text: &str) -> Option<String> {
+    let mut chars = String::with_capacity(text.len());
+    let mut input = text.chars();
+
+    while let Some(c) = input.next() {
+        if c != '\\' {
+            chars.push(c);
+            continue;
+        }
+        match input.next()? {
+            'n' => chars.push('\n'),
+            't' => chars.push('\t'),
+            'r' => chars.push('\r'),
+            '0' => chars.push('\0'),
+            '"' => chars.push('"'),
+            '\\' => chars.push('\\'),
+            _ => return None,
+        }
+    }
+
+    Some(chars)
 }
@@ -1,18 +1,19 @@
 use std::convert::TryInto;
 
 use crate::chunk::{Chunk, OpCode};
+use crate::memory::Heap;
 use crate::value::print_value;
 
-pub fn dissassemble_chunk(chunk: &Chunk, name: &str) {
+pub fn dissassemble_chunk(chunk: &Chunk, name: &str, heap: &Heap) {
     println!("== {} ==", name);
 
     let mut offset = 0usize;
     while offset < chunk.code.len() {
-        offset = dissassemble_instruction(chunk, offset);
+        offset = dissassemble_instruction(chunk, offset, heap);
     }
 }
 
-pub fn dissassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
+pub fn dissassemble_instruction(chunk: &Chunk, offset: usize, heap: &Heap) -> usize {
     print!("{:04} ", offset);
 
     if offset >= chunk.code.len() {
@@ -20,22 +21,57 @@ pub fn dissassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
         return offset;
     }
 
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
+    let line = chunk.line_at(offset);
+    if offset > 0 && line == chunk.line_at(offset - 1) {
         print!("   | ");
     } else {
-        print!("{:4} ", chunk.lines[offset]);
+        print!("{:4} ", line);
     }
 
     let instruction = chunk.get(offset);
     let instruction: OpCode = instruction.try_into().unwrap();
-    match instruction {
-        OpCode::OpConstant => constant_instruction("OP_CONSTANT", chunk, offset),
+    let long = instruction.is_long();
+    match instruction.short() {
+        OpCode::OpConstant => constant_instruction("OP_CONSTANT", chunk, offset, long, heap),
+        OpCode::OpNil => simple_instruction("OP_NIL", offset),
+        OpCode::OpTrue => simple_instruction("OP_TRUE", offset),
+        OpCode::OpFalse => simple_instruction("OP_FALSE", offset),
+        OpCode::OpPop => simple_instruction("OP_POP", offset),
+        OpCode::OpGetLocal => byte_instruction("OP_GET_LOCAL", chunk, offset),
+        OpCode::OpSetLocal => byte_instruction("OP_SET_LOCAL", chunk, offset),
+        OpCode::OpGetGlobal => constant_instruction("OP_GET_GLOBAL", chunk, offset, long, heap),
+        OpCode::OpDefineGlobal => {
+            constant_instruction("OP_DEFINE_GLOBAL", chunk, offset, long, heap)
+        }
+        OpCode::OpSetGlobal => constant_instruction("OP_SET_GLOBAL", chunk, offset, long, heap),
+        OpCode::OpGetUpvalue => byte_instruction("OP_GET_UPVALUE", chunk, offset),
+        OpCode::OpSetUpvalue => byte_instruction("OP_SET_UPVALUE", chunk, offset),
+        OpCode::OpGetProperty => constant_instruction("OP_GET_PROPERTY", chunk, offset, long, heap),
+        OpCode::OpSetProperty => constant_instruction("OP_SET_PROPERTY", chunk, offset, long, heap),
+        OpCode::OpGetSuper => constant_instruction("OP_GET_SUPER", chunk, offset, long, heap),
+        OpCode::OpEqual => simple_instruction("OP_EQUAL", offset),
+        OpCode::OpGreater => simple_instruction("OP_GREATER", offset),
+        OpCode::OpLess => simple_instruction("OP_LESS", offset),
         OpCode::OpAdd => simple_instruction("OP_ADD", offset),
         OpCode::OpSubtract => simple_instruction("OP_SUBTRACT", offset),
         OpCode::OpMultiply => simple_instruction("OP_MULTIPLY", offset),
         OpCode::OpDivide => simple_instruction("OP_DIVIDE", offset),
+        OpCode::OpNot => simple_instruction("OP_NOT", offset),
         OpCode::OpNegate => simple_instruction("OP_NEGATE", offset),
+        OpCode::OpPrint => simple_instruction("OP_PRINT", offset),
+        OpCode::OpJump => jump_instruction("OP_JUMP", 1, chunk, offset),
+        OpCode::OpJumpIfFalse => jump_instruction("OP_JUMP_IF_FALSE", 1, chunk, offset),
+        OpCode::OpLoop => jump_instruction("OP_LOOP", -1, chunk, offset),
+        OpCode::OpCall => byte_instruction("OP_CALL", chunk, offset),
+        OpCode::OpInvoke => invoke_instruction("OP_INVOKE", chunk, offset, long, heap),
+        OpCode::OpSuperInvoke => invoke_instruction("OP_SUPER_INVOKE", chunk, offset, long, heap),
+        OpCode::OpClosure => closure_instruction("OP_CLOSURE", chunk, offset, long, heap),
+        OpCode::OpCloseUpvalue => simple_instruction("OP_CLOSE_UPVALUE", offset),
         OpCode::OpReturn => simple_instruction("OP_RETURN", offset),
+        OpCode::OpClass => constant_instruction("OP_CLASS", chunk, offset, long, heap),
+        OpCode::OpInherit => simple_instruction("OP_INHERIT", offset),
+        OpCode::OpMethod => constant_instruction("OP_METHOD", chunk, offset, long, heap),
+        _ => unreachable!("short forms only"),
     }
 }
 
@@ -44,10 +80,95 @@ fn simple_instruction(name: &str, offset: usize) -> usize {
     offset + 1
 }
 
-fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
-    let constant = chunk.get(offset + 1);
-    print!("{:-16} {:04} '", name, constant);
-    print_value(chunk.constants[constant as usize]);
-    println!("'");
+fn byte_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let slot = chunk.get(offset + 1);
+    println!("{:-16} {:4}", name, slot);
     offset + 2
 }
+
+fn jump_instruction(name: &str, sign: isize, chunk: &Chunk, offset: usize) -> usize {
+    let jump = u16::from_be_bytes([chunk.get(offset + 1), chunk.get(offset + 2)]);
+    let target = offset as isize + 3 + sign * jump as isize;
+    println!("{:-16} {:4} -> {}", name, offset, target);
+    offset + 3
+}
+
+/// Read the constant index after the opcode at `offset`, returning it and
+/// the offset just past it.
+fn read_index(chunk: &Chunk, offset: usize, long: bool) -> (usize, usize) {
+    if long {
+        let index = u32::from_be_bytes([
+            0,
+            chunk.get(offset + 1),
+            chunk.get(offset + 2),
+            chunk.get(offset + 3),
+        ]);
+        (index as usize, offset + 4)
+    } else {
+        (chunk.get(offset + 1) as usize, offset + 2)
+    }
+}
+
+fn long_name(name: &str, long: bool) -> String {
+    if long {
+        format!("{}_LONG", name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn constant_instruction(
+    name: &str,
+    chunk: &Chunk,
+    offset: usize,
+    long: bool,
+    heap: &Heap,
+) -> usize {
+    let (constant, offset) = read_index(chunk, offset, long);
+    print!("{:-16} {:04} '", long_name(name, long), constant);
+    print_value(chunk.constants[constant], heap);
+    println!("'");
+    offset
+}
+
+fn invoke_instruction(name: &str, chunk: &Chunk, offset: usize, long: bool, heap: &Heap) -> usize {
+    let (constant, offset) = read_index(chunk, offset, long);
+    let arg_count = chunk.get(offset);
+    print!(
+        "{:-16} ({} args) {:04} '",
+        long_name(name, long),
+        arg_count,
+        constant
+    );
+    print_value(chunk.constants[constant], heap);
+    println!("'");
+    offset + 1
+}
+
+fn closure_instruction(name: &str, chunk: &Chunk, offset: usize, long: bool, heap: &Heap) -> usize {
+    let (constant, mut offset) = read_index(chunk, offset, long);
+
+    let function = chunk.constants[constant];
+    print!("{:-16} {:04} ", long_name(name, long), constant);
+    print_value(function, heap);
+    println!();
+
+    let upvalue_count = function
+        .as_obj()
+        .and_then(|function| heap.as_function(function))
+        .map(|function| function.upvalue_count)
+        .unwrap_or_default();
+    for _ in 0..upvalue_count {
+        let is_local = chunk.get(offset);
+        let index = chunk.get(offset + 1);
+        println!(
+            "{:04}    |                     {} {}",
+            offset,
+            if is_local == 1 { "local" } else { "upvalue" },
+            index
+        );
+        offset += 2;
+    }
+
+    offset
+}
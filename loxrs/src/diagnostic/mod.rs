@@ -0,0 +1,134 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+
+use crate::scanner::Token;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Severity {
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+        }
+    }
+}
+
+/// A problem found while scanning or compiling, along with where it is in the
+/// source.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub line: usize,
+    /// The 1-based column of the start of `span`, counted in characters.
+    pub column: usize,
+    /// The byte range in the source that the diagnostic is about.
+    pub span: Range<usize>,
+    /// The offending token's text, or `None` when the problem isn't a whole
+    /// token, like an unexpected character.
+    pub token: Option<String>,
+}
+
+impl Diagnostic {
+    /// An error at a token, which is empty at the end of the input.
+    pub fn at_token(source: &str, token: &Token, message: &str) -> Self {
+        let text = token.text(source).to_string();
+        Self::new(token.span(), token.line, token.column, message, Some(text))
+    }
+
+    /// An error at a span of source that didn't make a token.
+    pub fn at_span(span: Range<usize>, line: usize, column: usize, message: &str) -> Self {
+        Self::new(span, line, column, message, None)
+    }
+
+    fn new(
+        span: Range<usize>,
+        line: usize,
+        column: usize,
+        message: &str,
+        token: Option<String>,
+    ) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.to_string(),
+            line,
+            column,
+            span,
+            token,
+        }
+    }
+}
+
+/// Prints in the clox format: `[line N] Error at 'x': message`.
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] {}", self.line, self.severity)?;
+        match self.token {
+            Some(_) if self.span.is_empty() => write!(f, " at end")?,
+            Some(ref token) => write!(f, " at '{}'", token)?,
+            None => {}
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Identifies the source text a chunk was compiled from, so its spans are
+/// only ever shown against the text they index into.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct SourceId(u64);
+
+impl SourceId {
+    pub fn of(source: &str) -> Self {
+        let mut hasher = DefaultHasher::new();
+        source.hash(&mut hasher);
+        SourceId(hasher.finish())
+    }
+}
+
+/// An error raised while a script is running.
+#[derive(Debug, PartialEq, Clone)]
+pub struct RuntimeError {
+    pub message: String,
+    /// The line of the instruction that failed.
+    pub line: usize,
+    /// The source span of the instruction that failed, if there's a call
+    /// stack to take it from.
+    pub span: Option<Range<usize>>,
+    /// The source that `span` indexes into, if the failing chunk was compiled
+    /// from source.
+    pub source: Option<SourceId>,
+    /// The call stack when the error happened, innermost call first.
+    pub trace: Vec<TraceFrame>,
+}
+
+/// Prints the message followed by the stack trace, in the clox format.
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in self.trace.iter() {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceFrame {
+    /// The function's name, or `None` for the top-level script.
+    pub function: Option<String>,
+    pub line: usize,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.function {
+            Some(ref name) => write!(f, "[line {}] in {}()", self.line, name),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
@@ -4,16 +4,23 @@ use std::fmt;
 use std::io;
 use std::result;
 
+use crate::diagnostic::{self, Diagnostic};
+use crate::verifier;
+
 pub type Result<R> = result::Result<R, Error>;
 
 #[derive(Debug)]
 pub enum Error {
     MissingChunkError,
     InvalidOpCode(u8),
+    InvalidBytecode(String),
+    VerifyError(verifier::VerifyError),
+    /// A `Script` was given to a VM other than the one that made it.
+    ForeignScript,
     IoError(io::Error),
-    ScanError(char, usize),
-    CompileError,
-    RuntimeError,
+    ScanError(Diagnostic),
+    CompileError(Vec<Diagnostic>),
+    RuntimeError(diagnostic::RuntimeError),
 }
 
 use Error::*;
@@ -23,10 +30,18 @@ impl fmt::Display for Error {
         match self {
             MissingChunkError => write!(f, "Missing chunk error"),
             InvalidOpCode(value) => write!(f, "Invalid opcode: {}", value),
+            InvalidBytecode(message) => write!(f, "Invalid bytecode: {}", message),
+            VerifyError(err) => write!(f, "Invalid bytecode: {}", err),
+            ForeignScript => write!(f, "Script belongs to a different VM"),
             IoError(ref err) => err.fmt(f),
-            ScanError(c, line_no) => write!(f, "Scan error '{}' line {}", c, line_no),
-            CompileError => write!(f, "Compile error"),
-            RuntimeError => write!(f, "Runtime error"),
+            ScanError(diagnostic) => diagnostic.fmt(f),
+            CompileError(diagnostics) => {
+                for diagnostic in diagnostics {
+                    writeln!(f, "{}", diagnostic)?;
+                }
+                write!(f, "Compile error")
+            }
+            RuntimeError(err) => write!(f, "{}\nRuntime error", err),
         }
     }
 }
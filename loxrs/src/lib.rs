@@ -1,39 +1,87 @@
 use std::cell::RefCell;
 use std::fs;
-use std::io::{stdin, stdout, Write};
+use std::io::{self, stderr, stdin, stdout, IsTerminal, Write};
 use std::path::PathBuf;
 
-use crate::error::Result;
+use crate::error::{Error, Result};
 
+pub mod bytecode;
 pub mod chunk;
 pub mod compiler;
 pub mod debug;
+pub mod diagnostic;
 pub mod error;
+pub mod memory;
+pub mod native;
+pub mod object;
 pub mod scanner;
+pub mod snippet;
 pub mod value;
+pub mod verifier;
 pub mod vm;
 
+use crate::snippet::SnippetRenderer;
 use crate::vm::VM;
 
-pub fn repl() -> Result<()> {
+/// Run an interactive session. With `snippets`, errors are rendered with the
+/// offending source line rather than in the one-line clox format.
+pub fn repl(snippets: bool) -> Result<()> {
     let repl = Repl::new();
     loop {
         stdout().write_all(b"> ")?;
+        stdout().flush()?;
         let mut line = String::new();
-        stdin().read_line(&mut line)?;
-        repl.interpret(&line)?;
+        if stdin().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(());
+        }
+        // Report errors and keep going so the session's globals survive.
+        if let Err(err) = repl.interpret(&line) {
+            if snippets {
+                eprintln!("{}", render_error(&err, &line));
+            } else {
+                eprintln!("{}", err);
+            }
+        }
     }
 }
 
+/// Run a script, either as source or as bytecode saved by `compile_file`.
 pub fn run_file(file: PathBuf) -> Result<()> {
     let mut vm = VM::new();
-    let contents = fs::read_to_string(file)?;
+    let contents = fs::read(file)?;
 
-    vm.interpret(&contents)?;
+    if bytecode::is_bytecode(&contents) {
+        let script = bytecode::load(&mut vm, &contents)?;
+        vm.run_script(&script)?;
+    } else {
+        let contents = String::from_utf8(contents)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        vm.interpret(&contents)?;
+    }
 
     Ok(())
 }
 
+/// Compile a script to a `.loxc` file.
+pub fn compile_file(input: PathBuf, output: PathBuf) -> Result<()> {
+    let mut vm = VM::new();
+    let contents = fs::read_to_string(input)?;
+
+    let script = vm.compile(&contents)?;
+    fs::write(output, bytecode::save(&vm, &script)?)?;
+
+    Ok(())
+}
+
+/// Render an error against the source it came from, colouring it if stderr
+/// is a terminal.
+pub fn render_error(err: &Error, source: &str) -> String {
+    SnippetRenderer::new(source)
+        .with_colour(stderr().is_terminal())
+        .render_error(err)
+}
+
 struct Repl {
     vm: RefCell<VM>,
 }
@@ -1,12 +1,14 @@
+use std::fs;
 use std::path::PathBuf;
 use std::process;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use clap_verbosity_flag::Verbosity;
 use human_panic::setup_panic;
 
+use loxrs::bytecode;
 use loxrs::error::{Error, Result};
-use loxrs::{repl, run_file};
+use loxrs::{compile_file, render_error, repl, run_file};
 
 fn main() -> Result<()> {
     setup_panic!();
@@ -15,30 +17,70 @@ fn main() -> Result<()> {
         .filter_level(args.verbose.log_level_filter())
         .init();
 
-    if let Some(file) = args.file {
-        let result = run_file(file);
+    if let Some(Command::Compile { file, output }) = args.command {
+        let output = output.unwrap_or_else(|| file.with_extension("loxc"));
+        let result = compile_file(file.clone(), output);
         if let Err(err) = result {
-            eprintln!("{}", err);
-            match err {
-                Error::CompileError => process::exit(65),
-                Error::RuntimeError => process::exit(70),
-                Error::IoError(_) => process::exit(74),
-                _ => {}
-            }
+            report(err, &file, args.snippets);
+        }
+    } else if let Some(file) = args.file {
+        let result = run_file(file.clone());
+        if let Err(err) = result {
+            report(err, &file, args.snippets);
         }
     } else {
-        repl()?;
+        repl(args.snippets)?;
     }
 
     Ok(())
 }
 
+fn report(err: Error, file: &PathBuf, snippets: bool) {
+    // Bytecode files don't have any source to show.
+    match fs::read_to_string(file) {
+        Ok(source) if snippets && !bytecode::is_bytecode(source.as_bytes()) => {
+            eprintln!("{}", render_error(&err, &source))
+        }
+        _ => eprintln!("{}", err),
+    }
+    // The codes are from sysexits.h: bad input data, an internal software
+    // error, or an I/O error.
+    let code = match err {
+        Error::ScanError(_) | Error::CompileError(_) => 65,
+        Error::InvalidOpCode(_) | Error::InvalidBytecode(_) | Error::VerifyError(_) => 65,
+        Error::RuntimeError(_) => 70,
+        Error::MissingChunkError | Error::ForeignScript => 70,
+        Error::IoError(_) => 74,
+    };
+    process::exit(code);
+}
+
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(short, long)]
     file: Option<PathBuf>,
 
+    /// Show errors with the source line they're on, underlined.
+    #[arg(long)]
+    snippets: bool,
+
     #[command(flatten)]
     verbose: Verbosity,
 }
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Compile a script to bytecode that `--file` can run directly.
+    Compile {
+        file: PathBuf,
+
+        /// Where to write the bytecode. Defaults to the script's name with a
+        /// `.loxc` extension.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+
+use log;
+
+use crate::chunk::{LineRun, SpanRun};
+use crate::object::{
+    Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjRef, ObjString,
+    ObjUpvalue,
+};
+use crate::value::Value;
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+struct HeapEntry {
+    marked: bool,
+    /// The size the object is accounted for, as of when it was allocated or
+    /// last grew.
+    size: usize,
+    obj: Obj,
+}
+
+/// Owns every heap-allocated object and the string intern table.
+///
+/// The heap doesn't know what the roots are. Whoever owns it marks the roots
+/// with `mark_value` and `mark_object` and then calls `collect` to trace
+/// through the gray objects and sweep away everything left unmarked.
+pub struct Heap {
+    objects: Vec<Option<HeapEntry>>,
+    free_slots: Vec<usize>,
+    /// The keys share their buffers with the `ObjString`s they point to.
+    strings: HashMap<Rc<str>, ObjRef>,
+    gray_stack: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    stress_gc: bool,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            objects: Vec::new(),
+            free_slots: Vec::new(),
+            strings: HashMap::new(),
+            gray_stack: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+            stress_gc: false,
+        }
+    }
+
+    /// Collect before every allocation. This is slow, but it shakes out
+    /// objects that aren't reachable from the roots.
+    pub fn set_stress_gc(&mut self, stress_gc: bool) {
+        self.stress_gc = stress_gc;
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.objects.len() - self.free_slots.len()
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress_gc || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let size = object_size(&obj);
+        self.bytes_allocated += size;
+        let entry = HeapEntry {
+            marked: false,
+            size,
+            obj,
+        };
+
+        if let Some(index) = self.free_slots.pop() {
+            self.objects[index] = Some(entry);
+            ObjRef(index)
+        } else {
+            self.objects.push(Some(entry));
+            ObjRef(self.objects.len() - 1)
+        }
+    }
+
+    /// Re-measure an object that has grown since it was allocated, like an
+    /// instance gaining fields, so the collector knows about the extra
+    /// memory.
+    pub fn update_size(&mut self, reference: ObjRef) {
+        if let Some(entry) = self.objects[reference.0].as_mut() {
+            let size = object_size(&entry.obj);
+            self.bytes_allocated = self.bytes_allocated - entry.size + size;
+            entry.size = size;
+        }
+    }
+
+    /// Return the interned string for `chars`, copying it onto the heap if
+    /// it isn't there already.
+    pub fn intern(&mut self, chars: &str) -> ObjRef {
+        match self.strings.get(chars) {
+            Some(&reference) => reference,
+            None => self.take_string(chars.to_string()),
+        }
+    }
+
+    /// Like `intern`, but takes ownership of an already-built string.
+    pub fn take_string(&mut self, chars: String) -> ObjRef {
+        if let Some(&reference) = self.strings.get(chars.as_str()) {
+            return reference;
+        }
+        let chars: Rc<str> = chars.into();
+        let reference = self.alloc(Obj::String(ObjString::new(chars.clone())));
+        self.strings.insert(chars, reference);
+        reference
+    }
+
+    pub fn get(&self, reference: ObjRef) -> &Obj {
+        &self.objects[reference.0]
+            .as_ref()
+            .expect("dangling object reference")
+            .obj
+    }
+
+    pub fn get_mut(&mut self, reference: ObjRef) -> &mut Obj {
+        &mut self.objects[reference.0]
+            .as_mut()
+            .expect("dangling object reference")
+            .obj
+    }
+
+    pub fn as_string(&self, reference: ObjRef) -> Option<&ObjString> {
+        match self.get(reference) {
+            Obj::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_function(&self, reference: ObjRef) -> Option<&ObjFunction> {
+        match self.get(reference) {
+            Obj::Function(function) => Some(function),
+            _ => None,
+        }
+    }
+
+    pub fn as_closure(&self, reference: ObjRef) -> Option<&ObjClosure> {
+        match self.get(reference) {
+            Obj::Closure(closure) => Some(closure),
+            _ => None,
+        }
+    }
+
+    pub fn as_upvalue(&self, reference: ObjRef) -> Option<&ObjUpvalue> {
+        match self.get(reference) {
+            Obj::Upvalue(upvalue) => Some(upvalue),
+            _ => None,
+        }
+    }
+
+    pub fn as_upvalue_mut(&mut self, reference: ObjRef) -> Option<&mut ObjUpvalue> {
+        match self.get_mut(reference) {
+            Obj::Upvalue(upvalue) => Some(upvalue),
+            _ => None,
+        }
+    }
+
+    pub fn as_class(&self, reference: ObjRef) -> Option<&ObjClass> {
+        match self.get(reference) {
+            Obj::Class(class) => Some(class),
+            _ => None,
+        }
+    }
+
+    pub fn as_class_mut(&mut self, reference: ObjRef) -> Option<&mut ObjClass> {
+        match self.get_mut(reference) {
+            Obj::Class(class) => Some(class),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(&self, reference: ObjRef) -> Option<&ObjInstance> {
+        match self.get(reference) {
+            Obj::Instance(instance) => Some(instance),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance_mut(&mut self, reference: ObjRef) -> Option<&mut ObjInstance> {
+        match self.get_mut(reference) {
+            Obj::Instance(instance) => Some(instance),
+            _ => None,
+        }
+    }
+
+    pub fn as_bound_method(&self, reference: ObjRef) -> Option<&ObjBoundMethod> {
+        match self.get(reference) {
+            Obj::BoundMethod(bound) => Some(bound),
+            _ => None,
+        }
+    }
+
+    /// The name of a string object, for error messages and printing.
+    pub fn string_chars(&self, reference: ObjRef) -> &str {
+        self.as_string(reference)
+            .map(|string| &*string.chars)
+            .unwrap_or_default()
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Value::Obj(reference) = value {
+            self.mark_object(reference);
+        }
+    }
+
+    pub fn mark_object(&mut self, reference: ObjRef) {
+        if let Some(entry) = self.objects[reference.0].as_mut() {
+            if entry.marked {
+                return;
+            }
+            entry.marked = true;
+            self.gray_stack.push(reference);
+        }
+    }
+
+    /// Trace everything reachable from the marked roots, then free the rest.
+    pub fn collect(&mut self) {
+        let before = self.bytes_allocated;
+        log::debug!("-- gc begin");
+
+        self.trace_references();
+        self.remove_white_strings();
+        self.sweep();
+
+        self.next_gc = self.bytes_allocated * GC_HEAP_GROW_FACTOR;
+
+        log::debug!("-- gc end");
+        log::debug!(
+            "   collected {} bytes (from {} to {}) next at {}",
+            before - self.bytes_allocated,
+            before,
+            self.bytes_allocated,
+            self.next_gc
+        );
+    }
+
+    fn trace_references(&mut self) {
+        let mut children = Vec::new();
+        while let Some(reference) = self.gray_stack.pop() {
+            self.blacken_object(reference, &mut children);
+            for child in children.drain(..) {
+                self.mark_value(child);
+            }
+        }
+    }
+
+    fn blacken_object(&self, reference: ObjRef, children: &mut Vec<Value>) {
+        match self.get(reference) {
+            Obj::String(_) => {}
+            Obj::Function(function) => {
+                children.extend(function.name.map(Value::Obj));
+                children.extend(function.chunk.constants.iter().copied());
+            }
+            Obj::Closure(closure) => {
+                children.push(Value::Obj(closure.function));
+                children.extend(closure.upvalues.iter().copied().map(Value::Obj));
+            }
+            Obj::Upvalue(ObjUpvalue::Closed(value)) => children.push(*value),
+            Obj::Upvalue(ObjUpvalue::Open(_)) => {}
+            Obj::Class(class) => {
+                children.push(Value::Obj(class.name));
+                for (&name, &method) in class.methods.iter() {
+                    children.push(Value::Obj(name));
+                    children.push(method);
+                }
+            }
+            Obj::Instance(instance) => {
+                children.push(Value::Obj(instance.class));
+                for (&name, &value) in instance.fields.iter() {
+                    children.push(Value::Obj(name));
+                    children.push(value);
+                }
+            }
+            Obj::BoundMethod(bound) => {
+                children.push(bound.receiver);
+                children.push(Value::Obj(bound.method));
+            }
+            Obj::Native(native) => children.push(Value::Obj(native.name)),
+        }
+    }
+
+    /// The intern table holds its strings weakly, so drop the ones that are
+    /// about to be swept.
+    fn remove_white_strings(&mut self) {
+        let objects = &self.objects;
+        self.strings.retain(|_, reference| {
+            objects[reference.0]
+                .as_ref()
+                .is_some_and(|entry| entry.marked)
+        });
+    }
+
+    fn sweep(&mut self) {
+        for (index, slot) in self.objects.iter_mut().enumerate() {
+            match slot {
+                Some(entry) if entry.marked => entry.marked = false,
+                Some(entry) => {
+                    self.bytes_allocated -= entry.size;
+                    *slot = None;
+                    self.free_slots.push(index);
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Roughly how many bytes an object holds onto.
+fn object_size(obj: &Obj) -> usize {
+    let extra = match obj {
+        Obj::String(string) => string.chars.len(),
+        Obj::Function(function) => {
+            function.chunk.code.capacity()
+                + function.chunk.constants.capacity() * mem::size_of::<Value>()
+                + function.chunk.lines.capacity() * mem::size_of::<LineRun>()
+                + function.chunk.spans.capacity() * mem::size_of::<SpanRun>()
+        }
+        Obj::Closure(closure) => closure.upvalues.capacity() * mem::size_of::<ObjRef>(),
+        Obj::Class(class) => class.methods.capacity() * mem::size_of::<(ObjRef, Value)>(),
+        Obj::Instance(instance) => instance.fields.capacity() * mem::size_of::<(ObjRef, Value)>(),
+        Obj::Upvalue(_) | Obj::BoundMethod(_) | Obj::Native(_) => 0,
+    };
+    mem::size_of::<HeapEntry>() + extra
+}
@@ -0,0 +1,144 @@
+use std::io::{stdin, stdout, Write};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::value::Value;
+use crate::vm::VM;
+
+/// Register the built-in functions as globals on `vm`.
+pub fn define_stdlib(vm: &mut VM) {
+    vm.define_native("clock", 0, clock);
+    vm.define_native("str", 1, str);
+    vm.define_native("num", 1, num);
+    vm.define_native("len", 1, len);
+    vm.define_native("input", 0, input);
+    vm.define_native("exit", 1, exit);
+    vm.define_native("sqrt", 1, sqrt);
+    vm.define_native("floor", 1, floor);
+    vm.define_native("abs", 1, abs);
+    vm.define_native("random", 0, random);
+    vm.define_native("seed", 1, seed);
+}
+
+/// Seconds since the VM started.
+fn clock(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(vm.start_time.elapsed().as_secs_f64()))
+}
+
+fn str(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    if string_arg(vm, args[0]).is_some() {
+        return Ok(args[0]);
+    }
+    let chars = args[0].display(&vm.heap).to_string();
+    Ok(Value::Obj(vm.take_string(chars)))
+}
+
+/// Parse a string as a number, returning `nil` if it isn't one.
+fn num(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    if let Value::Number(_) = args[0] {
+        return Ok(args[0]);
+    }
+    let chars = string_arg(vm, args[0]).ok_or("Argument must be a string or number.")?;
+    Ok(chars
+        .trim()
+        .parse::<f64>()
+        .map(Value::Number)
+        .unwrap_or(Value::Nil))
+}
+
+fn len(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let chars = string_arg(vm, args[0]).ok_or("Argument must be a string.")?;
+    Ok(Value::Number(chars.chars().count() as f64))
+}
+
+/// Read a line from stdin without its line ending, or `nil` at end of input.
+fn input(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    stdout().flush().map_err(|err| err.to_string())?;
+    let mut line = String::new();
+    match stdin().read_line(&mut line) {
+        Ok(0) => Ok(Value::Nil),
+        Ok(_) => {
+            let length = line.trim_end_matches(&['\r', '\n'][..]).len();
+            line.truncate(length);
+            Ok(Value::Obj(vm.take_string(line)))
+        }
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn exit(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let code = number_arg(args[0])?;
+    // `process::exit` doesn't run destructors, so flush what's been printed.
+    let _ = stdout().flush();
+    process::exit(code as i32)
+}
+
+fn sqrt(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(number_arg(args[0])?.sqrt()))
+}
+
+fn floor(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(number_arg(args[0])?.floor()))
+}
+
+fn abs(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(number_arg(args[0])?.abs()))
+}
+
+/// A number in `[0, 1)`.
+fn random(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(vm.rng.next_f64()))
+}
+
+fn seed(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    vm.rng = Rng::new(number_arg(args[0])? as u64);
+    Ok(Value::Nil)
+}
+
+fn string_arg(vm: &VM, value: Value) -> Option<&str> {
+    value
+        .as_obj()
+        .and_then(|reference| vm.heap.as_string(reference))
+        .map(|string| &*string.chars)
+}
+
+fn number_arg(value: Value) -> Result<f64, String> {
+    value
+        .as_number()
+        .ok_or_else(|| "Argument must be a number.".to_string())
+}
+
+/// A small xorshift64* generator. It's not cryptographic, but it's fast and
+/// the same seed always gives the same sequence.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Xorshift gets stuck on zero, so scramble the seed first.
+        let state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        Self { state }
+    }
+
+    /// Seed from the system clock.
+    pub fn from_time() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos() as u64)
+            .unwrap_or_default();
+        Self::new(nanos)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn next_f64(&mut self) -> f64 {
+        // Use the top 53 bits so every result is exactly representable.
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::chunk::Chunk;
+use crate::value::Value;
+use crate::vm::VM;
+
+/// A handle to an object living on the `Heap`.
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+pub struct ObjRef(pub(crate) usize);
+
+pub enum Obj {
+    String(ObjString),
+    Function(ObjFunction),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+    Native(ObjNative),
+}
+
+#[derive(Debug)]
+pub struct ObjString {
+    /// Shared with the heap's intern table, so the text is only stored once.
+    pub chars: Rc<str>,
+}
+
+impl ObjString {
+    pub fn new(chars: Rc<str>) -> Self {
+        Self { chars }
+    }
+}
+
+pub struct ObjFunction {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    /// The compiled code. It's only mutable while the compiler is filling it
+    /// in; after that it's shared with the call frames running it.
+    pub chunk: Rc<Chunk>,
+    /// The function's name, or `None` for the top-level script.
+    pub name: Option<ObjRef>,
+}
+
+impl ObjFunction {
+    pub fn new(name: Option<ObjRef>) -> Self {
+        Self {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Rc::new(Chunk::new()),
+            name,
+        }
+    }
+}
+
+/// A function along with the variables it has captured from enclosing scopes.
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+impl ObjClosure {
+    pub fn new(function: ObjRef, upvalue_count: usize) -> Self {
+        Self {
+            function,
+            upvalues: Vec::with_capacity(upvalue_count),
+        }
+    }
+}
+
+/// A captured variable. It refers to a stack slot until that slot goes out of
+/// scope, at which point the value is moved into the upvalue itself.
+#[derive(Debug, Copy, Clone)]
+pub enum ObjUpvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+pub struct ObjClass {
+    pub name: ObjRef,
+    /// Method closures, keyed by their interned names.
+    pub methods: HashMap<ObjRef, Value>,
+}
+
+impl ObjClass {
+    pub fn new(name: ObjRef) -> Self {
+        Self {
+            name,
+            methods: HashMap::new(),
+        }
+    }
+}
+
+pub struct ObjInstance {
+    pub class: ObjRef,
+    pub fields: HashMap<ObjRef, Value>,
+}
+
+impl ObjInstance {
+    pub fn new(class: ObjRef) -> Self {
+        Self {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+/// A method closure that remembers the instance it was accessed from.
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+impl ObjBoundMethod {
+    pub fn new(receiver: Value, method: ObjRef) -> Self {
+        Self { receiver, method }
+    }
+}
+
+/// A function implemented in Rust. It gets the VM and its arguments, and an
+/// `Err` message becomes a Lox runtime error.
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, String>;
+
+pub struct ObjNative {
+    pub name: ObjRef,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl ObjNative {
+    pub fn new(name: ObjRef, arity: usize, function: NativeFn) -> Self {
+        Self {
+            name,
+            arity,
+            function,
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use std::char;
+use std::ops::Range;
 
+use crate::diagnostic::Diagnostic;
 use crate::error::{Error, Result};
 
 pub struct Scanner {
@@ -7,6 +9,13 @@ pub struct Scanner {
     pub start: usize,
     pub current: usize,
     pub line: usize,
+    /// The column of `current`, kept up to date by `advance` and `new_line`
+    /// so finding a token's column doesn't mean rescanning its line.
+    column: usize,
+    /// The column of the token being scanned.
+    start_column: usize,
+    /// Whether the iterator has handed out the EOF token.
+    finished: bool,
 }
 
 #[derive(Debug, PartialEq, Copy, Clone)]
@@ -57,29 +66,37 @@ pub enum TokenType {
     Var,
     While,
 
-    Error,
     EOF,
 }
 
+#[derive(Debug, Copy, Clone)]
 pub struct Token {
     pub ty: TokenType,
     pub offset: usize,
     pub length: usize,
     pub line: usize,
+    /// The 1-based column the token starts at, counted in characters.
+    pub column: usize,
 }
 
 impl Token {
-    fn new(ty: TokenType, offset: usize, length: usize, line: usize) -> Self {
+    fn new(ty: TokenType, offset: usize, length: usize, line: usize, column: usize) -> Self {
         Self {
             ty,
             offset,
             length,
             line,
+            column,
         }
     }
 
-    pub fn text<'a>(&self, _input: &'a str) -> &'a str {
-        todo!()
+    /// The token's byte range in the source.
+    pub fn span(&self) -> Range<usize> {
+        self.offset..self.offset + self.length
+    }
+
+    pub fn text<'a>(&self, input: &'a str) -> &'a str {
+        &input[self.offset..self.offset + self.length]
     }
 }
 
@@ -90,6 +107,9 @@ impl Scanner {
             start: 0,
             current: 0,
             line: 1,
+            column: 1,
+            start_column: 1,
+            finished: false,
         }
     }
 
@@ -99,6 +119,9 @@ impl Scanner {
             start: 0,
             current: 0,
             line,
+            column: 1,
+            start_column: 1,
+            finished: false,
         }
     }
 
@@ -106,13 +129,14 @@ impl Scanner {
         self.skip_whitespace();
 
         self.start = self.current;
+        self.start_column = self.column;
 
         if self.is_at_end() {
             return Ok(self.make_token(TokenType::EOF));
         }
 
         let c = self.advance();
-        if c.is_ascii_alphabetic() || c == '_' {
+        if is_identifier_start(c) {
             return self.identifier();
         }
         if c.is_ascii_digit() {
@@ -132,14 +156,14 @@ impl Scanner {
             '/' => Ok(self.make_token(TokenType::Slash)),
             '*' => Ok(self.make_token(TokenType::Star)),
 
-            '!' => self.match_second("=", TokenType::BangEqual, TokenType::Bang),
-            '=' => self.match_second("=", TokenType::EqualEqual, TokenType::Equal),
-            '<' => self.match_second("=", TokenType::LessEqual, TokenType::Less),
-            '>' => self.match_second("=", TokenType::GreaterEqual, TokenType::Greater),
+            '!' => self.match_second('=', TokenType::BangEqual, TokenType::Bang),
+            '=' => self.match_second('=', TokenType::EqualEqual, TokenType::Equal),
+            '<' => self.match_second('=', TokenType::LessEqual, TokenType::Less),
+            '>' => self.match_second('=', TokenType::GreaterEqual, TokenType::Greater),
 
             '"' => self.string(),
 
-            c => Err(self.error_token(c, self.line)),
+            c => Err(self.error(&format!("Unexpected character '{}'.", c))),
         }
     }
 
@@ -148,48 +172,48 @@ impl Scanner {
             let c = self.peek();
             match c {
                 ' ' | '\r' | '\t' => {}
-                '\n' => self.line += 1,
+                '\n' => {
+                    self.advance();
+                    self.new_line();
+                    continue;
+                }
                 '/' => {
                     if self.peek_next() == '/' {
                         while self.peek() != '\n' && !self.is_at_end() {
                             self.advance();
                         }
+                        continue;
                     } else {
                         break;
                     }
                 }
                 _ => break,
             }
-            self.current += 1;
+            self.advance();
         }
     }
 
+    /// The next character, or `'\0'` at the end of the input. `current` is
+    /// always on a character boundary, since `advance` steps over whole
+    /// characters.
     fn peek(&self) -> char {
-        self.input[self.current..self.current + 1]
-            .chars()
-            .next()
-            .unwrap_or('\0')
+        self.input[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
-        self.input[self.current + 1..self.current + 2]
-            .chars()
-            .next()
-            .unwrap_or('\0')
+        self.input[self.current..].chars().nth(1).unwrap_or('\0')
     }
 
     pub fn advance(&mut self) -> char {
-        self.current += 1;
-        // TODO: handle if a character is encoded as more than one byte.
-        self.input[self.current - 1..self.current]
-            .chars()
-            .next()
-            .unwrap()
+        let c = self.peek();
+        self.current += c.len_utf8();
+        self.column += 1;
+        c
     }
 
     fn match_second(
         &mut self,
-        expected: &str,
+        expected: char,
         matches: TokenType,
         does_not_match: TokenType,
     ) -> Result<Token> {
@@ -200,43 +224,64 @@ impl Scanner {
         }
     }
 
-    fn match_char(&mut self, expected: &str) -> bool {
-        if self.is_at_end() {
-            return false;
-        }
-        if self.input[self.current..self.current + 1] != *expected {
+    fn match_char(&mut self, expected: char) -> bool {
+        if self.is_at_end() || self.peek() != expected {
             return false;
         }
-        self.current += 1;
+        self.advance();
         true
     }
 
     fn string(&mut self) -> Result<Token> {
         while self.peek() != '"' && !self.is_at_end() {
+            // Skip over the backslash so an escaped quote doesn't end the
+            // literal. The compiler translates the escape sequences.
+            if self.peek() == '\\' {
+                self.advance();
+                if self.is_at_end() {
+                    break;
+                }
+            }
             if self.peek() == '\n' {
-                self.line += 1;
+                self.advance();
+                self.new_line();
+                continue;
             }
             self.advance();
         }
 
         if self.is_at_end() {
-            return Err(self.error_token('"', self.line));
+            return Err(self.error("Unterminated string."));
         }
 
         self.advance();
         Ok(self.make_token(TokenType::String))
     }
 
+    /// Call just after stepping over a newline.
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.column = 1;
+    }
+
     fn is_at_end(&self) -> bool {
         self.current >= self.input.len()
     }
 
     fn make_token(&self, ty: TokenType) -> Token {
-        Token::new(ty, self.start, self.current, self.line)
+        Token::new(
+            ty,
+            self.start,
+            self.current - self.start,
+            self.line,
+            self.start_column,
+        )
     }
 
-    fn error_token(&self, c: char, line_no: usize) -> Error {
-        Error::ScanError(c, line_no)
+    fn error(&self, message: &str) -> Error {
+        let span = self.start..self.current;
+        let diagnostic = Diagnostic::at_span(span, self.line, self.start_column, message);
+        Error::ScanError(diagnostic)
     }
 
     fn number(&mut self) -> Result<Token> {
@@ -255,7 +300,7 @@ impl Scanner {
     }
 
     fn identifier(&mut self) -> Result<Token> {
-        while self.peek().is_ascii_alphanumeric() || self.peek() == '_' {
+        while is_identifier_part(self.peek()) {
             self.advance();
         }
         Ok(self.make_token(self.identifier_type()))
@@ -279,7 +324,7 @@ impl Scanner {
             'w' => self.check_keyword(input, "while", TokenType::While),
 
             'f' => {
-                let second_char = input.chars().nth(1).unwrap();
+                let second_char = input.chars().nth(1).unwrap_or_default();
                 match second_char {
                     'a' => self.check_keyword(input, "false", TokenType::False),
                     'o' => self.check_keyword(input, "for", TokenType::For),
@@ -289,7 +334,7 @@ impl Scanner {
             }
 
             't' => {
-                let second_char = input.chars().nth(1).unwrap();
+                let second_char = input.chars().nth(1).unwrap_or_default();
                 match second_char {
                     'h' => self.check_keyword(input, "this", TokenType::This),
                     'r' => self.check_keyword(input, "true", TokenType::True),
@@ -309,16 +354,58 @@ impl Scanner {
         }
     }
 
-    pub fn scan_to_next(&mut self) -> (Option<Token>, Vec<(char, usize)>) {
+    /// Scan the next token, collecting any scan errors on the way to it.
+    /// Returns `None` for the token once EOF has been returned.
+    pub fn scan_to_next(&mut self) -> (Option<Token>, Vec<Diagnostic>) {
         let mut errors = Vec::new();
 
-        loop {
-            let token = self.scan_token();
-            match token {
+        while let Some(result) = self.next() {
+            match result {
                 Ok(token) => return (Some(token), errors),
-                Err(Error::ScanError(c, line_no)) => errors.push((c, line_no)),
-                Err(_) => {}
+                Err(Error::ScanError(diagnostic)) => errors.push(diagnostic),
+                Err(err) => {
+                    let span = self.start..self.current;
+                    let message = err.to_string();
+                    errors.push(Diagnostic::at_span(
+                        span,
+                        self.line,
+                        self.start_column,
+                        &message,
+                    ));
+                }
             }
         }
+        (None, errors)
+    }
+
+    /// Scan the rest of the input, stopping at the first error.
+    pub fn tokens(self) -> Result<Vec<Token>> {
+        self.collect()
+    }
+}
+
+/// Yields every token up to and including a single EOF, with scan errors
+/// mixed in where they happen.
+impl Iterator for Scanner {
+    type Item = Result<Token>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let result = self.scan_token();
+        if matches!(result, Ok(ref token) if token.ty == TokenType::EOF) {
+            self.finished = true;
+        }
+        Some(result)
     }
 }
+
+/// Identifiers can use any alphabetic character, not just ASCII.
+fn is_identifier_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+fn is_identifier_part(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
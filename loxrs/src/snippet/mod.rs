@@ -0,0 +1,175 @@
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::diagnostic::{Diagnostic, RuntimeError, SourceId};
+use crate::error::Error;
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Renders errors with the source line they point at and a row of carets
+/// under the offending span, like this:
+///
+/// ```text
+/// error: Expect expression.
+///  --> line 2, column 12
+///   |
+/// 2 |   print a +;
+///   |            ^
+/// ```
+pub struct SnippetRenderer<'a> {
+    source: &'a str,
+    colour: bool,
+}
+
+impl<'a> SnippetRenderer<'a> {
+    pub fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            colour: false,
+        }
+    }
+
+    /// Use ANSI colours. Usually only wanted when stderr is a terminal.
+    pub fn with_colour(mut self, colour: bool) -> Self {
+        self.colour = colour;
+        self
+    }
+
+    /// Render any error. Errors without a source location fall back to their
+    /// plain `Display`.
+    pub fn render_error(&self, err: &Error) -> String {
+        match err {
+            Error::ScanError(diagnostic) => {
+                let out = self.render_diagnostic(diagnostic);
+                out.trim_end_matches('\n').to_string()
+            }
+            Error::CompileError(diagnostics) => {
+                let mut out = String::new();
+                for diagnostic in diagnostics {
+                    out.push_str(&self.render_diagnostic(diagnostic));
+                }
+                out.push_str("Compile error");
+                out
+            }
+            Error::RuntimeError(err) => {
+                let mut out = self.render_runtime_error(err);
+                out.push_str("Runtime error");
+                out
+            }
+            _ => err.to_string(),
+        }
+    }
+
+    pub fn render_diagnostic(&self, diagnostic: &Diagnostic) -> String {
+        let severity = diagnostic.severity.to_string().to_lowercase();
+        let mut out = self.header(&severity, &diagnostic.message);
+        self.snippet(&mut out, diagnostic.line, Some(diagnostic.span.clone()));
+        out
+    }
+
+    /// The stack trace follows the snippet. In the REPL the failing code may
+    /// have been compiled from an earlier line, and its lines and spans don't
+    /// point into this source, so the error is rendered plainly instead.
+    pub fn render_runtime_error(&self, err: &RuntimeError) -> String {
+        if err.source != Some(SourceId::of(self.source)) {
+            return format!("{}\n", err);
+        }
+
+        let mut out = self.header("error", &err.message);
+        self.snippet(&mut out, err.line, err.span.clone());
+        for frame in err.trace.iter() {
+            let _ = writeln!(out, "{}", frame);
+        }
+        out
+    }
+
+    fn header(&self, severity: &str, message: &str) -> String {
+        format!(
+            "{}{}:{} {}{}{}\n",
+            self.paint(RED),
+            severity,
+            self.paint(RESET),
+            self.paint(BOLD),
+            message,
+            self.paint(RESET)
+        )
+    }
+
+    fn snippet(&self, out: &mut String, line: usize, span: Option<Range<usize>>) {
+        let located = match span {
+            // Go by the span rather than the line, since a token like an
+            // unterminated string is reported on the line where it ends.
+            Some(span) if self.source.get(span.clone()).is_some() => {
+                let line_start = self.source[..span.start].rfind('\n').map_or(0, |i| i + 1);
+                let line_end = self.source[line_start..]
+                    .find('\n')
+                    .map_or(self.source.len(), |i| line_start + i);
+                let text = &self.source[line_start..line_end];
+                let line = self.source[..line_start].matches('\n').count() + 1;
+                let start = self.source[line_start..span.start].chars().count();
+                // Only underline as far as the end of the first line.
+                let end = span.end.min(line_end).max(span.start);
+                let width = self.source[span.start..end].chars().count().max(1);
+                Some((line, text, start, width, true))
+            }
+            _ => line
+                .checked_sub(1)
+                .and_then(|i| self.source.lines().nth(i))
+                .map(|text| {
+                    let start = text.chars().take_while(|c| c.is_whitespace()).count();
+                    let width = text.trim().chars().count().max(1);
+                    (line, text, start, width, false)
+                }),
+        };
+        let (line, text, start, width, has_column) = match located {
+            Some(located) => located,
+            None => return,
+        };
+
+        // Keep tabs so the carets line up with the text above them.
+        let indent: String = text
+            .chars()
+            .take(start)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let gutter = " ".repeat(line.to_string().len());
+        let (blue, red, reset) = (self.paint(BLUE), self.paint(RED), self.paint(RESET));
+        if has_column {
+            let _ = writeln!(
+                out,
+                "{}{}-->{} line {}, column {}",
+                gutter,
+                blue,
+                reset,
+                line,
+                start + 1
+            );
+        } else {
+            let _ = writeln!(out, "{}{}-->{} line {}", gutter, blue, reset, line);
+        }
+        let _ = writeln!(out, "{} {}|{}", gutter, blue, reset);
+        let _ = writeln!(out, "{}{} |{} {}", blue, line, reset, text);
+        let _ = writeln!(
+            out,
+            "{} {}|{} {}{}{}{}",
+            gutter,
+            blue,
+            reset,
+            indent,
+            red,
+            "^".repeat(width),
+            reset
+        );
+    }
+
+    fn paint(&self, code: &'static str) -> &'static str {
+        if self.colour {
+            code
+        } else {
+            ""
+        }
+    }
+}
@@ -1,4 +1,130 @@
-pub type Value = f64;
+use std::fmt;
+
+use crate::memory::Heap;
+use crate::object::{Obj, ObjRef};
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(ObjRef),
+}
+
+impl Value {
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    pub fn is_bool(&self) -> bool {
+        matches!(self, Value::Bool(_))
+    }
+
+    pub fn is_number(&self) -> bool {
+        matches!(self, Value::Number(_))
+    }
+
+    pub fn is_obj(&self) -> bool {
+        matches!(self, Value::Obj(_))
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_obj(&self) -> Option<ObjRef> {
+        match self {
+            Value::Obj(reference) => Some(*reference),
+            _ => None,
+        }
+    }
+
+    /// `nil` and `false` are falsey; every other value is truthy.
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    /// Objects live on the heap, so printing a value needs access to it.
+    pub fn display<'a>(&self, heap: &'a Heap) -> DisplayValue<'a> {
+        DisplayValue { value: *self, heap }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Number(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<ObjRef> for Value {
+    fn from(reference: ObjRef) -> Self {
+        Value::Obj(reference)
+    }
+}
+
+pub struct DisplayValue<'a> {
+    value: Value,
+    heap: &'a Heap,
+}
+
+impl<'a> fmt::Display for DisplayValue<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Number(value) => write!(f, "{}", value),
+            Value::Obj(reference) => match self.heap.get(reference) {
+                Obj::String(string) => write!(f, "{}", string.chars),
+                Obj::Function(_) => self.write_function(f, reference),
+                Obj::Closure(closure) => self.write_function(f, closure.function),
+                Obj::Upvalue(_) => write!(f, "upvalue"),
+                Obj::Class(class) => write!(f, "{}", self.heap.string_chars(class.name)),
+                Obj::Instance(instance) => {
+                    let name = self.heap.as_class(instance.class).map(|class| class.name);
+                    let name = name.map(|name| self.heap.string_chars(name));
+                    write!(f, "{} instance", name.unwrap_or_default())
+                }
+                Obj::BoundMethod(bound) => {
+                    let function = self.heap.as_closure(bound.method).map(|c| c.function);
+                    match function {
+                        Some(function) => self.write_function(f, function),
+                        None => write!(f, "<fn>"),
+                    }
+                }
+                Obj::Native(_) => write!(f, "<native fn>"),
+            },
+        }
+    }
+}
+
+impl<'a> DisplayValue<'a> {
+    fn write_function(&self, f: &mut fmt::Formatter<'_>, function: ObjRef) -> fmt::Result {
+        match self
+            .heap
+            .as_function(function)
+            .and_then(|function| function.name)
+        {
+            Some(name) => write!(f, "<fn {}>", self.heap.string_chars(name)),
+            None => write!(f, "<script>"),
+        }
+    }
+}
 
 pub struct ValueArray {
     pub values: Vec<Value>,
@@ -24,6 +150,6 @@ impl Default for ValueArray {
     }
 }
 
-pub fn print_value(value: f64) {
-    print!("{}", value);
+pub fn print_value(value: Value, heap: &Heap) {
+    print!("{}", value.display(heap));
 }
@@ -0,0 +1,498 @@
+//! Checks that a function's bytecode is safe for the VM to run, since `run`
+//! trusts its chunk completely. This matters for chunks that didn't come
+//! straight from the compiler, like ones loaded from a `.loxc` file.
+//!
+//! The check is in two passes. The first decodes every instruction in order,
+//! checking opcodes, operands and constants, and records where instructions
+//! start. The second follows every path through the code from the entry point,
+//! tracking how many values are on the stack, so it can check jumps, local
+//! slots and stack depths.
+
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::chunk::{Chunk, OpCode};
+use crate::memory::Heap;
+use crate::object::{ObjFunction, ObjRef};
+use crate::value::Value;
+
+/// How deeply functions can nest inside each other's constants. The compiler
+/// won't go past this either, so anything it produces can be loaded.
+pub const NESTING_MAX: usize = 64;
+
+/// Why a function's bytecode was rejected.
+#[derive(Debug, PartialEq, Clone)]
+pub struct VerifyError {
+    /// The function's name, or `None` for the top-level script.
+    pub function: Option<String>,
+    /// The offset of the offending instruction.
+    pub offset: usize,
+    pub kind: VerifyErrorKind,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum VerifyErrorKind {
+    /// A constant in the function's chunk wasn't a function.
+    NotAFunction,
+    InvalidOpCode(u8),
+    /// The instruction's operands run past the end of the code.
+    Truncated,
+    ConstantOutOfRange(usize),
+    /// The constant isn't the type the instruction needs.
+    WrongConstantType {
+        index: usize,
+        expected: &'static str,
+    },
+    LocalOutOfRange(usize),
+    UpvalueOutOfRange(usize),
+    /// A closure's upvalue was neither a local (1) nor an upvalue (0).
+    InvalidUpvalueKind(u8),
+    /// A jump leaves the code. `None` for a loop back past the start.
+    JumpOutOfBounds(Option<usize>),
+    /// A jump lands in the middle of an instruction.
+    JumpIntoInstruction(usize),
+    /// Execution can run past the last instruction without returning.
+    FallsOffEnd,
+    StackUnderflow {
+        depth: usize,
+        needed: usize,
+    },
+    /// Two paths reach the instruction with different stack depths.
+    InconsistentStackDepth {
+        expected: usize,
+        found: usize,
+    },
+    /// Functions are nested more than `NESTING_MAX` deep.
+    NestedTooDeeply,
+    /// The top-level function takes parameters or captures upvalues, which
+    /// nothing could supply.
+    NotAScript,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.function {
+            Some(ref name) => write!(f, "in {}() ", name)?,
+            None => write!(f, "in script ")?,
+        }
+        write!(f, "at offset {}: {}", self.offset, self.kind)
+    }
+}
+
+impl fmt::Display for VerifyErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use VerifyErrorKind::*;
+
+        match self {
+            NotAFunction => write!(f, "expected a function"),
+            InvalidOpCode(byte) => write!(f, "invalid opcode {}", byte),
+            Truncated => write!(f, "instruction runs past the end of the code"),
+            ConstantOutOfRange(index) => write!(f, "no constant {}", index),
+            WrongConstantType { index, expected } => {
+                write!(f, "constant {} isn't a {}", index, expected)
+            }
+            LocalOutOfRange(slot) => write!(f, "no local in slot {}", slot),
+            UpvalueOutOfRange(index) => write!(f, "no upvalue {}", index),
+            InvalidUpvalueKind(kind) => write!(f, "invalid upvalue kind {}", kind),
+            JumpOutOfBounds(Some(target)) => write!(f, "jump to {} is out of bounds", target),
+            JumpOutOfBounds(None) => write!(f, "loop jumps before the start of the code"),
+            JumpIntoInstruction(target) => {
+                write!(f, "jump to {} lands inside an instruction", target)
+            }
+            FallsOffEnd => write!(f, "execution runs off the end of the code"),
+            StackUnderflow { depth, needed } => write!(
+                f,
+                "needs {} values on the stack but there are {}",
+                needed, depth
+            ),
+            InconsistentStackDepth { expected, found } => write!(
+                f,
+                "stack depth is {} on one path and {} on another",
+                expected, found
+            ),
+            NestedTooDeeply => write!(f, "functions are nested too deeply"),
+            NotAScript => write!(f, "a script can't take parameters or capture upvalues"),
+        }
+    }
+}
+
+/// Verify a top-level script and every function nested in its constants.
+pub fn verify(heap: &Heap, function: ObjRef) -> Result<(), VerifyError> {
+    if let Some(script) = heap.as_function(function) {
+        if script.arity != 0 || script.upvalue_count != 0 {
+            return Err(VerifyError {
+                function: script.name.map(|name| heap.string_chars(name).to_string()),
+                offset: 0,
+                kind: VerifyErrorKind::NotAScript,
+            });
+        }
+    }
+
+    let mut seen = HashSet::new();
+    verify_nested(heap, function, &mut seen, 0)
+}
+
+fn verify_nested(
+    heap: &Heap,
+    reference: ObjRef,
+    seen: &mut HashSet<ObjRef>,
+    depth: usize,
+) -> Result<(), VerifyError> {
+    // Hand-built chunks could refer to a function more than once, or even to
+    // themselves.
+    if !seen.insert(reference) {
+        return Ok(());
+    }
+
+    let function = heap.as_function(reference).ok_or(VerifyError {
+        function: None,
+        offset: 0,
+        kind: VerifyErrorKind::NotAFunction,
+    })?;
+    if depth > NESTING_MAX {
+        return Err(VerifyError {
+            function: function
+                .name
+                .map(|name| heap.string_chars(name).to_string()),
+            offset: 0,
+            kind: VerifyErrorKind::NestedTooDeeply,
+        });
+    }
+    Verifier::new(heap, function).verify()?;
+
+    for constant in function.chunk.constants.iter() {
+        if let Some(nested) = constant.as_obj() {
+            if heap.as_function(nested).is_some() {
+                verify_nested(heap, nested, seen, depth + 1)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A decoded instruction.
+struct Instruction {
+    offset: usize,
+    /// The short form of the opcode. The long forms only differ in how wide
+    /// their constant index is.
+    op: OpCode,
+    /// How many bytes the instruction takes up, including its operands.
+    length: usize,
+    /// The byte operand of the ops that have one, like a slot or an
+    /// argument count.
+    operand: usize,
+    /// Where a jump goes.
+    target: Option<usize>,
+    /// A closure's captures, as `(is_local, index)`.
+    upvalues: Vec<(bool, usize)>,
+}
+
+struct Verifier<'a> {
+    heap: &'a Heap,
+    function: &'a ObjFunction,
+    chunk: &'a Chunk,
+}
+
+impl<'a> Verifier<'a> {
+    fn new(heap: &'a Heap, function: &'a ObjFunction) -> Self {
+        Self {
+            heap,
+            function,
+            chunk: &function.chunk,
+        }
+    }
+
+    fn verify(&self) -> Result<(), VerifyError> {
+        let instructions = self.decode()?;
+        self.check_stack(&instructions)
+    }
+
+    fn decode(&self) -> Result<Vec<Instruction>, VerifyError> {
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        while offset < self.chunk.code.len() {
+            let instruction = self.decode_instruction(offset)?;
+            offset += instruction.length;
+            instructions.push(instruction);
+        }
+        Ok(instructions)
+    }
+
+    fn decode_instruction(&self, offset: usize) -> Result<Instruction, VerifyError> {
+        use OpCode::*;
+
+        let byte = self.chunk.code[offset];
+        let op = OpCode::try_from(byte)
+            .map_err(|_| self.error(offset, VerifyErrorKind::InvalidOpCode(byte)))?;
+        let long = op.is_long();
+        // The width of a constant index operand.
+        let width = if long { 3 } else { 1 };
+        let mut instruction = Instruction {
+            offset,
+            op: op.short(),
+            length: 1,
+            operand: 0,
+            target: None,
+            upvalues: Vec::new(),
+        };
+
+        match instruction.op {
+            OpConstant => {
+                let index = self.index(offset, long)?;
+                self.constant(offset, index)?;
+                instruction.length += width;
+            }
+            OpGetLocal | OpSetLocal | OpCall => {
+                instruction.operand = self.byte(offset, 1)?;
+                instruction.length = 2;
+            }
+            OpGetUpvalue | OpSetUpvalue => {
+                instruction.operand = self.byte(offset, 1)?;
+                self.upvalue(offset, instruction.operand)?;
+                instruction.length = 2;
+            }
+            OpGetGlobal | OpDefineGlobal | OpSetGlobal | OpGetProperty | OpSetProperty
+            | OpGetSuper | OpClass | OpMethod => {
+                let index = self.index(offset, long)?;
+                self.string_constant(offset, index)?;
+                instruction.length += width;
+            }
+            OpInvoke | OpSuperInvoke => {
+                let index = self.index(offset, long)?;
+                self.string_constant(offset, index)?;
+                instruction.operand = self.byte(offset, 1 + width)?;
+                instruction.length += width + 1;
+            }
+            OpJump | OpJumpIfFalse | OpLoop => {
+                instruction.length = 3;
+                let jump = (self.byte(offset, 1)? << 8) | self.byte(offset, 2)?;
+                let target = if op == OpLoop {
+                    (offset + 3).checked_sub(jump)
+                } else {
+                    Some(offset + 3 + jump)
+                };
+                match target {
+                    Some(target) if target < self.chunk.code.len() => {
+                        instruction.target = Some(target)
+                    }
+                    _ => return Err(self.error(offset, VerifyErrorKind::JumpOutOfBounds(target))),
+                }
+            }
+            OpClosure => {
+                let index = self.index(offset, long)?;
+                let function = self
+                    .constant(offset, index)?
+                    .as_obj()
+                    .and_then(|function| self.heap.as_function(function))
+                    .ok_or_else(|| self.wrong_type(offset, index, "function"))?;
+
+                for i in 0..function.upvalue_count {
+                    let is_local = self.byte(offset, 1 + width + i * 2)?;
+                    let index = self.byte(offset, 2 + width + i * 2)?;
+                    match is_local {
+                        1 => instruction.upvalues.push((true, index)),
+                        0 => {
+                            self.upvalue(offset, index)?;
+                            instruction.upvalues.push((false, index));
+                        }
+                        kind => {
+                            return Err(
+                                self.error(offset, VerifyErrorKind::InvalidUpvalueKind(kind as u8))
+                            )
+                        }
+                    }
+                }
+                instruction.length += width + instruction.upvalues.len() * 2;
+            }
+            // Everything else is a single byte.
+            _ => {}
+        }
+
+        Ok(instruction)
+    }
+
+    /// Walk every path from the entry point, checking that each instruction
+    /// has what it needs on the stack and is always reached with the same
+    /// depth.
+    fn check_stack(&self, instructions: &[Instruction]) -> Result<(), VerifyError> {
+        use OpCode::*;
+
+        // Map offsets to instructions, so jumps into the middle of one show up.
+        let mut indices = vec![None; self.chunk.code.len()];
+        for (index, instruction) in instructions.iter().enumerate() {
+            indices[instruction.offset] = Some(index);
+        }
+
+        // The callee (or receiver) and the arguments are already in the
+        // frame's first slots.
+        let entry = self.function.arity + 1;
+        let mut depths: Vec<Option<usize>> = vec![None; instructions.len()];
+        let mut pending = Vec::new();
+        if instructions.is_empty() {
+            return Err(self.error(0, VerifyErrorKind::FallsOffEnd));
+        }
+        depths[0] = Some(entry);
+        pending.push(0);
+
+        while let Some(index) = pending.pop() {
+            let instruction = &instructions[index];
+            let offset = instruction.offset;
+            let depth = depths[index].unwrap_or(entry);
+
+            let (pops, pushes) = stack_effect(instruction);
+            // Slot zero belongs to the frame, so nothing can pop it.
+            if depth < pops + 1 {
+                return Err(self.error(
+                    offset,
+                    VerifyErrorKind::StackUnderflow {
+                        depth: depth - 1,
+                        needed: pops,
+                    },
+                ));
+            }
+            match instruction.op {
+                OpGetLocal | OpSetLocal if instruction.operand >= depth => {
+                    return Err(self.error(
+                        offset,
+                        VerifyErrorKind::LocalOutOfRange(instruction.operand),
+                    ));
+                }
+                OpClosure => {
+                    for &(is_local, slot) in instruction.upvalues.iter() {
+                        if is_local && slot >= depth {
+                            return Err(self.error(offset, VerifyErrorKind::LocalOutOfRange(slot)));
+                        }
+                    }
+                }
+                _ => {}
+            }
+            let depth = depth - pops + pushes;
+
+            let mut successors = Vec::with_capacity(2);
+            if let Some(target) = instruction.target {
+                successors.push(target);
+            }
+            if !matches!(instruction.op, OpJump | OpLoop | OpReturn) {
+                let next = offset + instruction.length;
+                if next >= self.chunk.code.len() {
+                    return Err(self.error(offset, VerifyErrorKind::FallsOffEnd));
+                }
+                successors.push(next);
+            }
+
+            for successor in successors {
+                let next = indices[successor].ok_or_else(|| {
+                    self.error(offset, VerifyErrorKind::JumpIntoInstruction(successor))
+                })?;
+                match depths[next] {
+                    Some(expected) if expected != depth => {
+                        return Err(self.error(
+                            successor,
+                            VerifyErrorKind::InconsistentStackDepth {
+                                expected,
+                                found: depth,
+                            },
+                        ))
+                    }
+                    Some(_) => {}
+                    None => {
+                        depths[next] = Some(depth);
+                        pending.push(next);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The byte `at` past the instruction at `offset`.
+    fn byte(&self, offset: usize, at: usize) -> Result<usize, VerifyError> {
+        self.chunk
+            .code
+            .get(offset + at)
+            .map(|&byte| byte as usize)
+            .ok_or_else(|| self.error(offset, VerifyErrorKind::Truncated))
+    }
+
+    /// The constant index after the opcode at `offset`.
+    fn index(&self, offset: usize, long: bool) -> Result<usize, VerifyError> {
+        if long {
+            let high = self.byte(offset, 1)?;
+            let middle = self.byte(offset, 2)?;
+            let low = self.byte(offset, 3)?;
+            Ok((high << 16) | (middle << 8) | low)
+        } else {
+            self.byte(offset, 1)
+        }
+    }
+
+    fn constant(&self, offset: usize, index: usize) -> Result<Value, VerifyError> {
+        self.chunk
+            .constants
+            .get(index)
+            .copied()
+            .ok_or_else(|| self.error(offset, VerifyErrorKind::ConstantOutOfRange(index)))
+    }
+
+    fn string_constant(&self, offset: usize, index: usize) -> Result<(), VerifyError> {
+        match self.constant(offset, index)?.as_obj() {
+            Some(string) if self.heap.as_string(string).is_some() => Ok(()),
+            _ => Err(self.wrong_type(offset, index, "string")),
+        }
+    }
+
+    fn upvalue(&self, offset: usize, index: usize) -> Result<(), VerifyError> {
+        if index < self.function.upvalue_count {
+            Ok(())
+        } else {
+            Err(self.error(offset, VerifyErrorKind::UpvalueOutOfRange(index)))
+        }
+    }
+
+    fn wrong_type(&self, offset: usize, index: usize, expected: &'static str) -> VerifyError {
+        self.error(
+            offset,
+            VerifyErrorKind::WrongConstantType { index, expected },
+        )
+    }
+
+    fn error(&self, offset: usize, kind: VerifyErrorKind) -> VerifyError {
+        VerifyError {
+            function: self
+                .function
+                .name
+                .map(|name| self.heap.string_chars(name).to_string()),
+            offset,
+            kind,
+        }
+    }
+}
+
+/// How many values an instruction pops and then pushes. The long forms
+/// have already been shortened, since they have the same effect.
+fn stack_effect(instruction: &Instruction) -> (usize, usize) {
+    use OpCode::*;
+
+    let arg_count = instruction.operand;
+    match instruction.op {
+        OpConstant | OpNil | OpTrue | OpFalse | OpGetLocal | OpGetGlobal | OpGetUpvalue
+        | OpClosure | OpClass => (0, 1),
+        OpPop | OpDefineGlobal | OpPrint | OpCloseUpvalue | OpReturn => (1, 0),
+        OpSetLocal | OpSetGlobal | OpSetUpvalue | OpGetProperty | OpNot | OpNegate
+        | OpJumpIfFalse => (1, 1),
+        OpSetProperty | OpGetSuper | OpEqual | OpGreater | OpLess | OpAdd | OpSubtract
+        | OpMultiply | OpDivide => (2, 1),
+        // The subclass is popped, leaving the superclass in its local.
+        OpInherit => (2, 1),
+        // The method is popped, leaving the class.
+        OpMethod => (2, 1),
+        OpJump | OpLoop => (0, 0),
+        // The callee or receiver and the arguments become the result.
+        OpCall | OpInvoke => (arg_count + 1, 1),
+        // The superclass is popped too.
+        OpSuperInvoke => (arg_count + 2, 1),
+        _ => unreachable!("short forms only"),
+    }
+}
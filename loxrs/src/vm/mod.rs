@@ -1,104 +1,863 @@
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
 
 use log;
 
 use crate::chunk::{Chunk, OpCode};
 use crate::compiler;
 use crate::debug::dissassemble_instruction;
-use crate::error::Result;
-use crate::value::Value;
+use crate::diagnostic::{RuntimeError, TraceFrame};
+use crate::error::{Error, Result};
+use crate::memory::Heap;
+use crate::native::{self, Rng};
+use crate::object::{
+    NativeFn, Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjNative,
+    ObjRef, ObjUpvalue,
+};
+use crate::value::{print_value, Value};
+use crate::verifier;
 
 use OpCode::*;
 
 macro_rules! binary_op {
-    ($vm:ident, $op:tt) => {
+    ($vm:ident, $wrapper:expr, $op:tt) => {
         {
-            let b = $vm.pop();
-            let a = $vm.pop();
-            $vm.push(a $op b);
+            match ($vm.peek(1), $vm.peek(0)) {
+                (Value::Number(a), Value::Number(b)) => {
+                    $vm.pop();
+                    $vm.pop();
+                    $vm.push($wrapper(a $op b));
+                }
+                _ => return Err($vm.runtime_error("Operands must be numbers.")),
+            }
         }
     };
 }
 
-const STACK_MAX: usize = 256;
+/// The default limit on how deep calls can nest.
+pub const FRAMES_MAX: usize = 64;
+/// The default limit on how many values the stack can hold, enough for every
+/// frame to use all of its local slots.
+pub const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
+
+/// Hands out the ids that tie each `Script` to the VM that made it.
+static NEXT_VM_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A single ongoing function call.
+pub struct CallFrame {
+    pub closure: ObjRef,
+    /// The closure's code, kept here so reading an instruction doesn't have
+    /// to go through the heap.
+    pub chunk: Rc<Chunk>,
+    pub ip: usize,
+    /// The index of the first stack slot this call can use.
+    pub slots: usize,
+}
+
+/// A compiled top-level script that can be run any number of times. It lives
+/// on the heap of the VM that compiled it and only works with that VM.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Script {
+    pub(crate) function: ObjRef,
+    /// The id of the VM whose heap `function` is on.
+    vm_id: usize,
+}
 
 pub struct VM {
-    pub stack: [Value; STACK_MAX],
-    pub stack_top: usize,
+    /// Tells this VM's scripts apart from other VMs'.
+    id: usize,
+    pub frames: Vec<CallFrame>,
+    /// The value stack. It grows as needed, up to `stack_limit`.
+    pub stack: Vec<Value>,
+    pub heap: Heap,
+    pub globals: HashMap<ObjRef, Value>,
+    /// Upvalues still pointing at stack slots, ordered by slot.
+    pub open_upvalues: Vec<ObjRef>,
+    init_string: ObjRef,
+    /// The functions behind the live `Script`s, kept as GC roots.
+    scripts: Vec<ObjRef>,
+    /// When the VM was created, for `clock()`.
+    pub start_time: Instant,
+    /// The generator behind `random()`.
+    pub rng: Rng,
+    stack_limit: usize,
+    frame_limit: usize,
 }
 
 impl VM {
     pub fn new() -> VM {
-        VM {
-            stack: [0.0; STACK_MAX],
-            stack_top: 0,
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+
+        let mut vm = VM {
+            id: NEXT_VM_ID.fetch_add(1, Ordering::Relaxed),
+            frames: Vec::new(),
+            stack: Vec::with_capacity(u8::MAX as usize + 1),
+            heap,
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            init_string,
+            scripts: Vec::new(),
+            start_time: Instant::now(),
+            rng: Rng::from_time(),
+            stack_limit: STACK_MAX,
+            frame_limit: FRAMES_MAX,
+        };
+        native::define_stdlib(&mut vm);
+        vm
+    }
+
+    /// Make a Rust function callable from Lox as the global `name`.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        // Keep the name and function on the stack so a collection triggered
+        // by either allocation can't free them.
+        let name = self.take_string(name.to_string());
+        self.push(Value::Obj(name));
+        let native = self.alloc(Obj::Native(ObjNative::new(name, arity, function)));
+        self.push(Value::Obj(native));
+        self.globals.insert(name, Value::Obj(native));
+        self.pop();
+        self.pop();
+    }
+
+    /// Intern `chars`, collecting garbage first if it's time.
+    pub fn take_string(&mut self, chars: String) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
         }
+        self.heap.take_string(chars)
+    }
+
+    /// The most values the stack can hold before reporting a stack overflow.
+    pub fn set_stack_limit(&mut self, stack_limit: usize) {
+        self.stack_limit = stack_limit;
+    }
+
+    /// How deep calls can nest before reporting a stack overflow.
+    pub fn set_frame_limit(&mut self, frame_limit: usize) {
+        self.frame_limit = frame_limit;
     }
 
     pub fn reset(&mut self) {
-        self.stack_top = 0;
+        // Closures that escaped before an error still point into the stack,
+        // so move their values out before it goes.
+        self.close_upvalues(0);
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
     }
 
     pub fn interpret<S: AsRef<str>>(&mut self, source: S) -> Result<()> {
-        let mut chunk = compiler::compile(source.as_ref())?;
-        self.run(&mut chunk)
+        let function = compiler::compile(source.as_ref(), self)?;
+        self.run_function(function)
     }
 
-    fn run(&mut self, chunk: &mut Chunk) -> Result<()> {
+    /// Compile a script once so it can be run with `run_script` as often as
+    /// needed. It stays on the heap until it's given to `free_script`.
+    pub fn compile<S: AsRef<str>>(&mut self, source: S) -> Result<Script> {
+        let function = compiler::compile(source.as_ref(), self)?;
+        Ok(self.pin_script(function))
+    }
+
+    pub fn run_script(&mut self, script: &Script) -> Result<()> {
+        self.check_owner(script)?;
+        self.run_function(script.function)
+    }
+
+    /// Let the garbage collector have a script back.
+    pub fn free_script(&mut self, script: Script) -> Result<()> {
+        self.check_owner(&script)?;
+        if let Some(index) = self.scripts.iter().position(|&f| f == script.function) {
+            self.scripts.swap_remove(index);
+        }
+        Ok(())
+    }
+
+    /// A script's function is an index into one VM's heap, so on any other
+    /// VM it would name some unrelated object.
+    pub(crate) fn check_owner(&self, script: &Script) -> Result<()> {
+        if script.vm_id == self.id {
+            Ok(())
+        } else {
+            Err(Error::ForeignScript)
+        }
+    }
+
+    /// Turn a function that didn't come from `compile`, like a hand-built
+    /// one, into a script. Its bytecode is checked first, since `run_script`
+    /// trusts it completely.
+    pub fn load_function(&mut self, function: ObjRef) -> Result<Script> {
+        verifier::verify(&self.heap, function).map_err(Error::VerifyError)?;
+        Ok(self.pin_script(function))
+    }
+
+    pub(crate) fn pin_script(&mut self, function: ObjRef) -> Script {
+        self.scripts.push(function);
+        Script {
+            function,
+            vm_id: self.id,
+        }
+    }
+
+    fn run_function(&mut self, function: ObjRef) -> Result<()> {
+        self.push(Value::Obj(function));
+        let closure = self.alloc(Obj::Closure(ObjClosure::new(function, 0)));
+        self.pop();
+        self.push(Value::Obj(closure));
+        self.call(closure, 0)?;
+        self.run()
+    }
+
+    fn run(&mut self) -> Result<()> {
         loop {
             if log::max_level() >= log::Level::Trace {
                 self.print_stack();
-                dissassemble_instruction(chunk, chunk.ip);
+                dissassemble_instruction(self.chunk(), self.frame().ip, &self.heap);
             }
 
-            if chunk.at_end() {
-                break;
+            // No instruction pushes more than a couple of values, so checking
+            // here keeps the stack from growing much past its limit.
+            if self.stack.len() > self.stack_limit {
+                return Err(self.runtime_error("Stack overflow."));
             }
-            let instruction = chunk.read_op_code();
-            match OpCode::try_from(instruction)? {
-                OpConstant => {
-                    let constant = chunk.read_constant();
+
+            let instruction = self.read_byte();
+            let op = match OpCode::try_from(instruction) {
+                Ok(op) => op,
+                Err(_) => {
+                    let message = format!("Invalid opcode {}.", instruction);
+                    return Err(self.runtime_error(&message));
+                }
+            };
+            // Long instructions only differ in how wide their constant
+            // index is.
+            let long = op.is_long();
+            match op {
+                OpConstant | OpConstantLong => {
+                    let constant = self.read_constant(long);
                     self.push(constant);
                 }
-                OpAdd => binary_op!(self, +),
-                OpSubtract => binary_op!(self, -),
-                OpMultiply => binary_op!(self, *),
-                OpDivide => binary_op!(self, /),
-                OpNegate => {
+                OpNil => self.push(Value::Nil),
+                OpTrue => self.push(Value::Bool(true)),
+                OpFalse => self.push(Value::Bool(false)),
+                OpPop => {
+                    self.pop();
+                }
+                OpGetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let slots = self.frame().slots;
+                    self.push(self.stack[slots + slot]);
+                }
+                OpSetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let slots = self.frame().slots;
+                    self.stack[slots + slot] = self.peek(0);
+                }
+                OpGetGlobal | OpGetGlobalLong => {
+                    let name = self.read_string(long);
+                    match self.globals.get(&name) {
+                        Some(&value) => self.push(value),
+                        None => {
+                            let message = self.undefined_variable(name);
+                            return Err(self.runtime_error(&message));
+                        }
+                    }
+                }
+                OpDefineGlobal | OpDefineGlobalLong => {
+                    let name = self.read_string(long);
                     let value = self.pop();
-                    self.push(-value);
+                    self.globals.insert(name, value);
                 }
-                OpReturn => {
+                OpSetGlobal | OpSetGlobalLong => {
+                    let name = self.read_string(long);
+                    let value = self.peek(0);
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = value,
+                        None => {
+                            let message = self.undefined_variable(name);
+                            return Err(self.runtime_error(&message));
+                        }
+                    }
+                }
+                OpGetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.frame_upvalue(slot);
+                    let value = match self.heap.as_upvalue(upvalue) {
+                        Some(ObjUpvalue::Open(location)) => self.stack[*location],
+                        Some(ObjUpvalue::Closed(value)) => *value,
+                        None => Value::Nil,
+                    };
+                    self.push(value);
+                }
+                OpSetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.frame_upvalue(slot);
+                    let value = self.peek(0);
+                    match self.heap.as_upvalue_mut(upvalue) {
+                        Some(ObjUpvalue::Open(location)) => {
+                            let location = *location;
+                            self.stack[location] = value;
+                        }
+                        Some(ObjUpvalue::Closed(closed)) => *closed = value,
+                        None => {}
+                    }
+                }
+                OpGetProperty | OpGetPropertyLong => {
+                    let instance = match self.peek(0).as_obj() {
+                        Some(instance) if self.heap.as_instance(instance).is_some() => instance,
+                        _ => return Err(self.runtime_error("Only instances have properties.")),
+                    };
+                    let name = self.read_string(long);
+
+                    let field = self
+                        .heap
+                        .as_instance(instance)
+                        .and_then(|instance| instance.fields.get(&name).copied());
+                    if let Some(value) = field {
+                        self.pop();
+                        self.push(value);
+                    } else {
+                        let class = self.instance_class(instance);
+                        self.bind_method(class, name)?;
+                    }
+                }
+                OpSetProperty | OpSetPropertyLong => {
+                    let instance = match self.peek(1).as_obj() {
+                        Some(instance) if self.heap.as_instance(instance).is_some() => instance,
+                        _ => return Err(self.runtime_error("Only instances have fields.")),
+                    };
+                    let name = self.read_string(long);
+
+                    let value = self.peek(0);
+                    if let Some(object) = self.heap.as_instance_mut(instance) {
+                        object.fields.insert(name, value);
+                    }
+                    self.heap.update_size(instance);
                     let value = self.pop();
-                    self.print_value(value);
+                    self.pop();
+                    self.push(value);
+                }
+                OpGetSuper | OpGetSuperLong => {
+                    let name = self.read_string(long);
+                    let superclass = self.pop_superclass()?;
+                    self.bind_method(superclass, name)?;
+                }
+                OpEqual => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::Bool(a == b));
+                }
+                OpGreater => binary_op!(self, Value::Bool, >),
+                OpLess => binary_op!(self, Value::Bool, <),
+                OpAdd => match (self.peek(1), self.peek(0)) {
+                    (Value::Number(a), Value::Number(b)) => {
+                        self.pop();
+                        self.pop();
+                        self.push(Value::Number(a + b));
+                    }
+                    (Value::Obj(a), Value::Obj(b))
+                        if self.heap.as_string(a).is_some() && self.heap.as_string(b).is_some() =>
+                    {
+                        self.concatenate(a, b);
+                    }
+                    _ => {
+                        return Err(
+                            self.runtime_error("Operands must be two numbers or two strings.")
+                        )
+                    }
+                },
+                OpSubtract => binary_op!(self, Value::Number, -),
+                OpMultiply => binary_op!(self, Value::Number, *),
+                OpDivide => binary_op!(self, Value::Number, /),
+                OpNot => {
+                    let value = self.pop();
+                    self.push(Value::Bool(value.is_falsey()));
+                }
+                OpNegate => match self.peek(0) {
+                    Value::Number(value) => {
+                        self.pop();
+                        self.push(Value::Number(-value));
+                    }
+                    _ => return Err(self.runtime_error("Operand must be a number.")),
+                },
+                OpPrint => {
+                    let value = self.pop();
+                    print_value(value, &self.heap);
                     println!();
                 }
+                OpJump => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip += offset as usize;
+                }
+                OpJumpIfFalse => {
+                    let offset = self.read_short();
+                    if self.peek(0).is_falsey() {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                OpLoop => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip -= offset as usize;
+                }
+                OpCall => {
+                    let arg_count = self.read_byte() as usize;
+                    self.call_value(self.peek(arg_count), arg_count)?;
+                }
+                OpInvoke | OpInvokeLong => {
+                    let method = self.read_string(long);
+                    let arg_count = self.read_byte() as usize;
+                    self.invoke(method, arg_count)?;
+                }
+                OpSuperInvoke | OpSuperInvokeLong => {
+                    let method = self.read_string(long);
+                    let arg_count = self.read_byte() as usize;
+                    let superclass = self.pop_superclass()?;
+                    self.invoke_from_class(superclass, method, arg_count)?;
+                }
+                OpClosure | OpClosureLong => {
+                    let function = match self.read_constant(long).as_obj() {
+                        Some(function) if self.heap.as_function(function).is_some() => function,
+                        _ => return Err(self.runtime_error("Can only close over functions.")),
+                    };
+                    let upvalue_count = self
+                        .heap
+                        .as_function(function)
+                        .map(|function| function.upvalue_count)
+                        .unwrap_or_default();
+                    let mut closure = ObjClosure::new(function, upvalue_count);
+
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        let upvalue = if is_local {
+                            let slots = self.frame().slots;
+                            self.capture_upvalue(slots + index)
+                        } else {
+                            self.frame_upvalue(index)
+                        };
+                        closure.upvalues.push(upvalue);
+                    }
+
+                    let closure = self.alloc(Obj::Closure(closure));
+                    self.push(Value::Obj(closure));
+                }
+                OpCloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpReturn => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().expect("returning without a call frame");
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+                    if self.frames.is_empty() {
+                        return Ok(());
+                    }
+
+                    self.push(result);
+                }
+                OpClass | OpClassLong => {
+                    let name = self.read_string(long);
+                    let class = self.alloc(Obj::Class(ObjClass::new(name)));
+                    self.push(Value::Obj(class));
+                }
+                OpInherit => {
+                    let superclass = match self.peek(1).as_obj() {
+                        Some(superclass) if self.heap.as_class(superclass).is_some() => superclass,
+                        _ => return Err(self.runtime_error("Superclass must be a class.")),
+                    };
+                    let subclass = match self.peek(0).as_obj() {
+                        Some(subclass) if self.heap.as_class(subclass).is_some() => subclass,
+                        _ => return Err(self.runtime_error("Subclass must be a class.")),
+                    };
+
+                    // Copy the inherited methods down so lookups never have
+                    // to walk the superclass chain. The subclass's own
+                    // methods are defined afterwards and override these.
+                    let methods = self
+                        .heap
+                        .as_class(superclass)
+                        .map(|superclass| superclass.methods.clone())
+                        .unwrap_or_default();
+                    if let Some(class) = self.heap.as_class_mut(subclass) {
+                        class.methods.extend(methods);
+                    }
+                    self.heap.update_size(subclass);
+                    self.pop();
+                }
+                OpMethod | OpMethodLong => {
+                    let name = self.read_string(long);
+                    self.define_method(name);
+                }
+            }
+        }
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<()> {
+        if let Value::Obj(reference) = callee {
+            match self.heap.get(reference) {
+                Obj::BoundMethod(bound) => {
+                    let method = bound.method;
+                    // Methods find their receiver in slot zero.
+                    let callee = self.stack.len() - arg_count - 1;
+                    self.stack[callee] = bound.receiver;
+                    return self.call(method, arg_count);
+                }
+                Obj::Class(class) => {
+                    let initializer = class.methods.get(&self.init_string).copied();
+                    let instance = self.alloc(Obj::Instance(ObjInstance::new(reference)));
+                    let callee = self.stack.len() - arg_count - 1;
+                    self.stack[callee] = Value::Obj(instance);
+
+                    return match initializer {
+                        Some(Value::Obj(initializer)) => self.call(initializer, arg_count),
+                        // Only hand-built bytecode can define one of these.
+                        Some(_) => Err(self.runtime_error("Can only call functions and classes.")),
+                        None if arg_count != 0 => {
+                            let message = format!("Expected 0 arguments but got {}.", arg_count);
+                            Err(self.runtime_error(&message))
+                        }
+                        None => Ok(()),
+                    };
+                }
+                Obj::Closure(_) => return self.call(reference, arg_count),
+                Obj::Native(native) => {
+                    let (arity, function) = (native.arity, native.function);
+                    if arg_count != arity {
+                        let message =
+                            format!("Expected {} arguments but got {}.", arity, arg_count);
+                        return Err(self.runtime_error(&message));
+                    }
+
+                    // The arguments stay on the stack, and so stay rooted,
+                    // until the native returns.
+                    let args = self.stack[self.stack.len() - arg_count..].to_vec();
+                    let result = match function(self, &args) {
+                        Ok(result) => result,
+                        Err(message) => return Err(self.runtime_error(&message)),
+                    };
+                    self.stack.truncate(self.stack.len() - arg_count - 1);
+                    self.push(result);
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+        Err(self.runtime_error("Can only call functions and classes."))
+    }
+
+    /// Call the method `name` on the receiver sitting under the arguments,
+    /// without allocating a bound method. A field holding a callable wins
+    /// over a method with the same name.
+    fn invoke(&mut self, name: ObjRef, arg_count: usize) -> Result<()> {
+        let receiver = self.peek(arg_count);
+        let instance = match receiver.as_obj() {
+            Some(instance) if self.heap.as_instance(instance).is_some() => instance,
+            _ => return Err(self.runtime_error("Only instances have methods.")),
+        };
+
+        let field = self
+            .heap
+            .as_instance(instance)
+            .and_then(|instance| instance.fields.get(&name).copied());
+        if let Some(value) = field {
+            let callee = self.stack.len() - arg_count - 1;
+            self.stack[callee] = value;
+            return self.call_value(value, arg_count);
+        }
+
+        let class = self.instance_class(instance);
+        self.invoke_from_class(class, name, arg_count)
+    }
+
+    /// Call the method `name` from `class` directly, without creating a
+    /// bound method. The receiver is already in place on the stack.
+    fn invoke_from_class(&mut self, class: ObjRef, name: ObjRef, arg_count: usize) -> Result<()> {
+        let method = self
+            .heap
+            .as_class(class)
+            .and_then(|class| class.methods.get(&name).copied())
+            .and_then(|method| method.as_obj());
+        match method {
+            Some(method) => self.call(method, arg_count),
+            None => {
+                let message = format!("Undefined property '{}'.", self.heap.string_chars(name));
+                Err(self.runtime_error(&message))
             }
         }
+    }
+
+    fn instance_class(&self, instance: ObjRef) -> ObjRef {
+        self.heap
+            .as_instance(instance)
+            .map(|instance| instance.class)
+            .expect("instance without a class")
+    }
 
+    /// Pop the class a `super` expression looks methods up in.
+    fn pop_superclass(&mut self) -> Result<ObjRef> {
+        match self.pop().as_obj() {
+            Some(superclass) if self.heap.as_class(superclass).is_some() => Ok(superclass),
+            _ => Err(self.runtime_error("Superclass must be a class.")),
+        }
+    }
+
+    /// Replace the instance on top of the stack with its method `name`,
+    /// bound to that instance.
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<()> {
+        let method = self
+            .heap
+            .as_class(class)
+            .and_then(|class| class.methods.get(&name).copied())
+            .and_then(|method| method.as_obj());
+        let method = match method {
+            Some(method) => method,
+            None => {
+                let message = format!("Undefined property '{}'.", self.heap.string_chars(name));
+                return Err(self.runtime_error(&message));
+            }
+        };
+
+        let bound = ObjBoundMethod::new(self.peek(0), method);
+        let bound = self.alloc(Obj::BoundMethod(bound));
+        self.pop();
+        self.push(Value::Obj(bound));
         Ok(())
     }
 
-    fn print_value(&self, value: f64) {
-        print!("{}", value);
+    fn define_method(&mut self, name: ObjRef) {
+        let method = self.peek(0);
+        if let Some(reference) = self.peek(1).as_obj() {
+            if let Some(class) = self.heap.as_class_mut(reference) {
+                class.methods.insert(name, method);
+            }
+            self.heap.update_size(reference);
+        }
+        self.pop();
+    }
+
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> Result<()> {
+        // Hand-built bytecode can put anything in a class's methods.
+        let arity = match self.heap.as_closure(closure) {
+            Some(_) => self.closure_function(closure).arity,
+            None => return Err(self.runtime_error("Can only call functions and classes.")),
+        };
+        if arg_count != arity {
+            let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+            return Err(self.runtime_error(&message));
+        }
+
+        if self.frames.len() >= self.frame_limit {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        let chunk = Rc::clone(&self.closure_function(closure).chunk);
+        self.frames.push(CallFrame {
+            closure,
+            chunk,
+            ip: 0,
+            slots: self.stack.len() - arg_count - 1,
+        });
+        Ok(())
+    }
+
+    #[inline]
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("no active call frame")
+    }
+
+    #[inline]
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("no active call frame")
+    }
+
+    #[inline]
+    fn closure_function(&self, closure: ObjRef) -> &ObjFunction {
+        self.heap
+            .as_closure(closure)
+            .and_then(|closure| self.heap.as_function(closure.function))
+            .expect("closure without a function")
+    }
+
+    #[inline]
+    fn chunk(&self) -> &Chunk {
+        &self.frame().chunk
+    }
+
+    #[inline]
+    fn frame_upvalue(&self, index: usize) -> ObjRef {
+        self.heap
+            .as_closure(self.frame().closure)
+            .map(|closure| closure.upvalues[index])
+            .expect("call frame without a closure")
+    }
+
+    #[inline]
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frames.last_mut().expect("no active call frame");
+        frame.ip += 1;
+        frame.chunk.get(frame.ip - 1)
+    }
+
+    #[inline]
+    fn read_short(&mut self) -> u16 {
+        let high = self.read_byte();
+        let low = self.read_byte();
+        u16::from_be_bytes([high, low])
+    }
+
+    #[inline]
+    fn read_constant(&mut self, long: bool) -> Value {
+        let constant = if long {
+            let high = self.read_byte();
+            let middle = self.read_byte();
+            let low = self.read_byte();
+            u32::from_be_bytes([0, high, middle, low]) as usize
+        } else {
+            self.read_byte() as usize
+        };
+        self.chunk().constants[constant]
+    }
+
+    #[inline]
+    fn read_string(&mut self, long: bool) -> ObjRef {
+        match self.read_constant(long) {
+            Value::Obj(reference) => reference,
+            _ => unreachable!("variable names are always strings"),
+        }
+    }
+
+    /// Find or create the open upvalue for a stack slot, so that closures
+    /// capturing the same variable share it.
+    fn capture_upvalue(&mut self, location: usize) -> ObjRef {
+        let mut insert_at = self.open_upvalues.len();
+        for (i, &upvalue) in self.open_upvalues.iter().enumerate().rev() {
+            match self.heap.as_upvalue(upvalue) {
+                Some(ObjUpvalue::Open(slot)) if *slot == location => return upvalue,
+                Some(ObjUpvalue::Open(slot)) if *slot < location => break,
+                _ => insert_at = i,
+            }
+        }
+
+        let upvalue = self.alloc(Obj::Upvalue(ObjUpvalue::Open(location)));
+        self.open_upvalues.insert(insert_at, upvalue);
+        upvalue
+    }
+
+    /// Hoist every open upvalue at or above `last` off the stack.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&reference) = self.open_upvalues.last() {
+            let location = match self.heap.as_upvalue(reference) {
+                Some(ObjUpvalue::Open(location)) if *location >= last => *location,
+                _ => break,
+            };
+            let value = self.stack[location];
+            if let Some(upvalue) = self.heap.as_upvalue_mut(reference) {
+                *upvalue = ObjUpvalue::Closed(value);
+            }
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.alloc(obj)
+    }
+
+    /// Mark everything the VM can reach directly and free the rest of the
+    /// heap. Anything else that must survive has to be marked beforehand.
+    pub fn collect_garbage(&mut self) {
+        for &value in self.stack.iter() {
+            self.heap.mark_value(value);
+        }
+        for frame in self.frames.iter() {
+            self.heap.mark_object(frame.closure);
+        }
+        for &upvalue in self.open_upvalues.iter() {
+            self.heap.mark_object(upvalue);
+        }
+        for (&name, &value) in self.globals.iter() {
+            self.heap.mark_object(name);
+            self.heap.mark_value(value);
+        }
+        for &script in self.scripts.iter() {
+            self.heap.mark_object(script);
+        }
+        self.heap.mark_object(self.init_string);
+
+        self.heap.collect();
+    }
+
+    fn undefined_variable(&self, name: ObjRef) -> String {
+        format!("Undefined variable '{}'.", self.heap.string_chars(name))
+    }
+
+    fn concatenate(&mut self, a: ObjRef, b: ObjRef) {
+        let mut chars = String::new();
+        chars.push_str(self.heap.string_chars(a));
+        chars.push_str(self.heap.string_chars(b));
+        let result = self.take_string(chars);
+        self.pop();
+        self.pop();
+        self.push(Value::Obj(result));
+    }
+
+    /// Build a runtime error with the current call stack, and reset the VM
+    /// so it's ready to run something else.
+    fn runtime_error(&mut self, message: &str) -> Error {
+        let trace: Vec<TraceFrame> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let name = self.closure_function(frame.closure).name;
+                TraceFrame {
+                    function: name.map(|name| self.heap.string_chars(name).to_string()),
+                    line: frame.chunk.line_at(frame.ip - 1),
+                }
+            })
+            .collect();
+        let line = trace.first().map(|frame| frame.line).unwrap_or_default();
+        let span = self
+            .frames
+            .last()
+            .and_then(|frame| frame.chunk.span_at(frame.ip - 1));
+        let source = self.frames.last().and_then(|frame| frame.chunk.source);
+
+        self.reset();
+        Error::RuntimeError(RuntimeError {
+            message: message.to_string(),
+            line,
+            span,
+            source,
+            trace,
+        })
     }
 
     #[inline]
     fn push(&mut self, value: Value) {
-        self.stack[self.stack_top] = value;
-        self.stack_top += 1;
+        self.stack.push(value);
     }
 
     #[inline]
     fn pop(&mut self) -> Value {
-        self.stack_top -= 1;
-        self.stack[self.stack_top]
+        self.stack.pop().expect("stack underflow")
+    }
+
+    #[inline]
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
     }
 
     fn print_stack(&self) {
         print!("          ");
-        for i in 0..self.stack_top {
-            print!("[{}]", self.stack[i]);
+        for value in self.stack.iter() {
+            print!("[ {} ]", value.display(&self.heap));
         }
         println!();
     }
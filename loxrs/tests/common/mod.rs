@@ -0,0 +1,8 @@
+use loxrs::value::Value;
+use loxrs::vm::VM;
+
+/// The value of a global variable, if it's defined.
+pub fn global(vm: &mut VM, name: &str) -> Option<Value> {
+    let name = vm.heap.intern(name);
+    vm.globals.get(&name).copied()
+}
@@ -0,0 +1,139 @@
+use loxrs::bytecode;
+use loxrs::error::Error;
+use loxrs::value::Value;
+use loxrs::verifier::NESTING_MAX;
+use loxrs::vm::VM;
+
+mod common;
+
+use common::global;
+
+fn compile(source: &str) -> Vec<u8> {
+    let mut vm = VM::new();
+    let script = vm.compile(source).unwrap();
+    bytecode::save(&vm, &script).unwrap()
+}
+
+#[test]
+fn test_round_trip_runs_in_a_fresh_vm() {
+    let bytes = compile(
+        "class Counter {
+           init(start) { this.count = start; }
+           add(n) { this.count = this.count + n; return this; }
+         }
+         fun make(step) {
+           var total = 0;
+           fun next() { total = total + step; return total; }
+           return next;
+         }
+         var next = make(2.5);
+         next();
+         var result = Counter(next()).add(1).count;
+         var name = \"caf\" + \"é\";
+         var flags = !nil and true;",
+    );
+    assert!(bytecode::is_bytecode(&bytes));
+
+    let mut vm = VM::new();
+    let script = bytecode::load(&mut vm, &bytes).unwrap();
+    vm.run_script(&script).unwrap();
+
+    assert_eq!(global(&mut vm, "result"), Some(Value::Number(6.0)));
+    assert_eq!(global(&mut vm, "flags"), Some(Value::Bool(true)));
+    let name = global(&mut vm, "name").and_then(|value| value.as_obj());
+    assert_eq!(
+        name.map(|name| vm.heap.string_chars(name).to_string()),
+        Some("café".to_string())
+    );
+}
+
+#[test]
+fn test_runtime_errors_keep_their_lines() {
+    let bytes = compile("var a = 1;\n\nprint -\"a\";");
+    let mut vm = VM::new();
+    let script = bytecode::load(&mut vm, &bytes).unwrap();
+    match vm.run_script(&script) {
+        Err(Error::RuntimeError(err)) => assert_eq!(err.line, 3),
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+}
+
+fn assert_invalid(bytes: &[u8]) {
+    let mut vm = VM::new();
+    match bytecode::load(&mut vm, bytes) {
+        Err(Error::InvalidBytecode(_)) => {}
+        other => panic!("expected invalid bytecode, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_bad_magic() {
+    let mut bytes = compile("print 1;");
+    bytes[0] = b'X';
+    assert!(!bytecode::is_bytecode(&bytes));
+    assert_invalid(&bytes);
+}
+
+#[test]
+fn test_wrong_version() {
+    let mut bytes = compile("print 1;");
+    bytes[4] = bytes[4].wrapping_add(1);
+    assert_invalid(&bytes);
+}
+
+#[test]
+fn test_truncated_and_trailing_bytes() {
+    let bytes = compile("fun f() { return \"f\"; } print f();");
+    for length in 0..bytes.len() {
+        assert_invalid(&bytes[..length]);
+    }
+
+    let mut longer = bytes.clone();
+    longer.push(0);
+    assert_invalid(&longer);
+}
+
+/// Nested function declarations, `depth` deep.
+fn nested_functions(depth: usize) -> String {
+    let mut source = String::new();
+    for i in 0..depth {
+        source.push_str(&format!("fun f{}() {{ ", i));
+    }
+    source.push_str("print 1;");
+    source.push_str(&" }".repeat(depth));
+    source
+}
+
+#[test]
+fn test_nesting_depth_is_limited() {
+    // Anything the compiler accepts loads again.
+    let bytes = compile(&nested_functions(NESTING_MAX));
+    let mut vm = VM::new();
+    assert!(bytecode::load(&mut vm, &bytes).is_ok());
+
+    let mut vm = VM::new();
+    match vm.compile(nested_functions(NESTING_MAX + 1)) {
+        Err(Error::CompileError(diagnostics)) => {
+            assert_eq!(diagnostics[0].message, "Too many nested functions.")
+        }
+        other => panic!("expected a compile error, got {:?}", other.map(|_| ())),
+    }
+
+    // A malformed file nesting far deeper must not overflow the stack.
+    let mut bytes = bytecode::MAGIC.to_vec();
+    bytes.extend_from_slice(&bytecode::VERSION.to_le_bytes());
+    let depth = 200_000;
+    for level in 0..depth {
+        // No name, then arity, upvalue count, code, lines and spans.
+        bytes.push(0);
+        bytes.extend_from_slice(&[0; 20]);
+        if level + 1 < depth {
+            // A single constant: the next function.
+            bytes.extend_from_slice(&1u32.to_le_bytes());
+            bytes.push(5);
+        } else {
+            bytes.extend_from_slice(&0u32.to_le_bytes());
+        }
+    }
+    assert_invalid(&bytes);
+}
@@ -0,0 +1,81 @@
+use pretty_assertions::assert_eq;
+
+use loxrs::chunk::{Chunk, LineRun, SpanRun};
+
+/// Write one byte per entry in `lines`, returning the chunk.
+fn chunk_with_lines(lines: &[usize]) -> Chunk {
+    let mut chunk = Chunk::new();
+    for &line in lines {
+        chunk.write(0, line, 0..0);
+    }
+    chunk
+}
+
+#[test]
+fn test_line_runs() {
+    let chunk = chunk_with_lines(&[1, 1, 1, 2, 2, 4, 1]);
+
+    assert_eq!(
+        chunk.lines,
+        vec![
+            LineRun { start: 0, line: 1 },
+            LineRun { start: 3, line: 2 },
+            LineRun { start: 5, line: 4 },
+            LineRun { start: 6, line: 1 },
+        ]
+    );
+}
+
+#[test]
+fn test_line_at_matches_naive_encoding() {
+    // A deterministic mix of long runs, single bytes and repeated lines.
+    let mut naive = Vec::new();
+    let mut seed: usize = 17;
+    for line in 1..200 {
+        seed = (seed * 1_103_515_245 + 12_345) % 2_147_483_648;
+        let count = seed % 7;
+        naive.extend(std::iter::repeat_n(line, count));
+        if seed.is_multiple_of(3) {
+            naive.push(line / 2 + 1);
+        }
+    }
+
+    let chunk = chunk_with_lines(&naive);
+
+    assert!(chunk.lines.len() < naive.len());
+    let decoded: Vec<usize> = (0..naive.len())
+        .map(|offset| chunk.line_at(offset))
+        .collect();
+    assert_eq!(decoded, naive);
+}
+
+#[test]
+fn test_span_runs() {
+    let mut chunk = Chunk::new();
+    // An instruction and its operand share a span, as does a run of
+    // instructions compiled from the same token.
+    for span in [0..3, 0..3, 4..5, 4..5, 4..5, 0..3] {
+        chunk.write(0, 1, span);
+    }
+
+    assert_eq!(
+        chunk.spans,
+        vec![
+            SpanRun {
+                start: 0,
+                span: 0..3
+            },
+            SpanRun {
+                start: 2,
+                span: 4..5
+            },
+            SpanRun {
+                start: 5,
+                span: 0..3
+            },
+        ]
+    );
+    assert_eq!(chunk.span_at(3), Some(4..5));
+    assert_eq!(chunk.span_at(5), Some(0..3));
+    assert_eq!(Chunk::new().span_at(0), None);
+}
@@ -0,0 +1,113 @@
+use pretty_assertions::assert_eq;
+
+use loxrs::compiler::compile;
+use loxrs::diagnostic::{Diagnostic, Severity, SourceId, TraceFrame};
+use loxrs::error::Error;
+use loxrs::snippet::SnippetRenderer;
+use loxrs::vm::VM;
+
+fn diagnostics(source: &str) -> Vec<Diagnostic> {
+    let mut vm = VM::new();
+    match compile(source, &mut vm) {
+        Err(Error::CompileError(diagnostics)) => diagnostics,
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("expected compile errors"),
+    }
+}
+
+#[test]
+fn test_diagnostic_fields() {
+    let diagnostics = diagnostics("var a = 1;\n  print a +;\n");
+
+    assert_eq!(
+        diagnostics,
+        vec![Diagnostic {
+            severity: Severity::Error,
+            message: "Expect expression.".to_string(),
+            line: 2,
+            column: 12,
+            span: 22..23,
+            token: Some(";".to_string()),
+        }]
+    );
+}
+
+#[test]
+fn test_diagnostic_at_end() {
+    let diagnostics = diagnostics("print 1");
+
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].span, 7..7);
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "[line 1] Error at end: Expect ';' after value."
+    );
+}
+
+#[test]
+fn test_synchronize_reports_every_statement() {
+    let diagnostics = diagnostics("var = 1;\nprint ;\nvar ok = 2;\nprint ok +;\n");
+
+    let lines: Vec<usize> = diagnostics.iter().map(|d| d.line).collect();
+    assert_eq!(lines, vec![1, 2, 4]);
+}
+
+#[test]
+fn test_runtime_error_trace() {
+    let mut vm = VM::new();
+    let source = "fun inner() {\n  return 1 + nil;\n}\nfun outer() {\n  inner();\n}\nouter();\n";
+
+    let err = match vm.interpret(source) {
+        Err(Error::RuntimeError(err)) => err,
+        other => panic!("expected a runtime error, got {:?}", other),
+    };
+
+    assert_eq!(err.message, "Operands must be two numbers or two strings.");
+    assert_eq!(err.line, 2);
+    // The addition is attributed to the `+`, not the end of its operands.
+    assert_eq!(err.span, Some(25..26));
+    assert_eq!(err.source, Some(SourceId::of(source)));
+    assert_eq!(
+        err.trace,
+        vec![
+            TraceFrame {
+                function: Some("inner".to_string()),
+                line: 2,
+            },
+            TraceFrame {
+                function: Some("outer".to_string()),
+                line: 5,
+            },
+            TraceFrame {
+                function: None,
+                line: 7,
+            },
+        ]
+    );
+
+    // The stack was reset, so the VM can keep going.
+    vm.interpret("print outer;").unwrap();
+}
+
+#[test]
+fn test_runtime_error_spans_belong_to_their_source() {
+    // Like a REPL, where a function from one line fails on a later one.
+    let mut vm = VM::new();
+    vm.interpret("fun f() { return 1 + nil; }").unwrap();
+    let line = "print \"a long string here\"; f();";
+    let err = match vm.interpret(line) {
+        Err(Error::RuntimeError(err)) => err,
+        other => panic!("expected a runtime error, got {:?}", other),
+    };
+
+    assert_eq!(
+        err.source,
+        Some(SourceId::of("fun f() { return 1 + nil; }"))
+    );
+    assert_eq!(
+        SnippetRenderer::new(line).render_runtime_error(&err),
+        "Operands must be two numbers or two strings.\n\
+         [line 1] in f()\n\
+         [line 1] in script\n"
+    );
+}
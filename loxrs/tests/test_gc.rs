@@ -0,0 +1,69 @@
+use std::fs;
+use std::mem;
+
+use loxrs::object::ObjRef;
+use loxrs::value::Value;
+use loxrs::vm::VM;
+
+mod common;
+
+use common::global;
+
+#[test]
+fn test_stress_gc_keeps_reachable_objects() {
+    let mut vm = VM::new();
+    vm.heap.set_stress_gc(true);
+
+    let source = fs::read_to_string("tests/scripts/closures.lox").unwrap();
+    vm.interpret(source).unwrap();
+    vm.interpret("var joined = \"a\" + \"b\" + \"c\";").unwrap();
+
+    let joined = global(&mut vm, "joined").and_then(|value| value.as_obj());
+    assert_eq!(
+        joined.map(|joined| vm.heap.string_chars(joined).to_string()),
+        Some("abc".to_string())
+    );
+}
+
+#[test]
+fn test_collect_frees_garbage() {
+    let mut vm = VM::new();
+    vm.interpret(
+        "var s = \"\";
+         for (var i = 0; i < 100; i = i + 1) { s = s + \"x\"; }
+         fun make() { var n = 1; fun get() { return n; } return get; }
+         var keep = make();",
+    )
+    .unwrap();
+
+    let before = vm.heap.object_count();
+    vm.collect_garbage();
+    assert!(vm.heap.object_count() < before);
+
+    vm.interpret("s = nil; print keep();").unwrap();
+    assert_eq!(global(&mut vm, "s"), Some(Value::Nil));
+}
+
+#[test]
+fn test_growing_an_instance_counts_toward_collection() {
+    let names: Vec<String> = (0..100).map(|i| format!("f{}", i)).collect();
+
+    let mut vm = VM::new();
+    // Intern the field names up front, as globals, so the only thing that
+    // grows later is the instance's field table.
+    let globals: String = names.iter().map(|name| format!("var {};", name)).collect();
+    vm.interpret(format!("class C {{}} var o = C(); {}", globals))
+        .unwrap();
+    vm.collect_garbage();
+    let before = vm.heap.bytes_allocated();
+
+    let fields: String = names
+        .iter()
+        .map(|name| format!("o.{} = 1;", name))
+        .collect();
+    vm.interpret(fields).unwrap();
+    vm.collect_garbage();
+
+    let table = names.len() * mem::size_of::<(ObjRef, Value)>();
+    assert!(vm.heap.bytes_allocated() >= before + table);
+}
@@ -2,6 +2,14 @@ use std::process::Command;
 
 use assert_cmd::prelude::*;
 
+fn run_script(name: &str) -> assert_cmd::assert::Assert {
+    Command::cargo_bin(env!["CARGO_PKG_NAME"])
+        .unwrap()
+        .arg("--file")
+        .arg(format!("tests/scripts/{}", name))
+        .assert()
+}
+
 #[test]
 fn test_run() {
     Command::cargo_bin(env!["CARGO_PKG_NAME"])
@@ -10,3 +18,366 @@ fn test_run() {
         .assert()
         .success();
 }
+
+#[test]
+fn test_arithmetic() {
+    run_script("arithmetic.lox").success().stdout("-2.75\n");
+}
+
+#[test]
+fn test_booleans() {
+    run_script("booleans.lox").success().stdout("true\n");
+}
+
+#[test]
+fn test_negate_bool() {
+    run_script("negate_bool.lox")
+        .code(70)
+        .stderr("Operand must be a number.\n[line 1] in script\nRuntime error\n");
+}
+
+#[test]
+fn test_strings() {
+    run_script("strings.lox")
+        .success()
+        .stdout("true\ntwo\nlines\nback\\slash\n");
+}
+
+#[test]
+fn test_unknown_escape() {
+    run_script("unknown_escape.lox")
+        .code(65)
+        .stderr("[line 1] Error at '\"a\\q\"': Invalid escape sequence.\nCompile error\n");
+}
+
+#[test]
+fn test_unterminated_escape() {
+    run_script("unterminated_escape.lox")
+        .code(65)
+        .stderr("[line 1] Error: Unterminated string.\nCompile error\n");
+}
+
+#[test]
+fn test_globals() {
+    run_script("globals.lox")
+        .success()
+        .stdout("beignets with cafe au lait\nnil\n1\n2\n");
+}
+
+#[test]
+fn test_undefined_global() {
+    run_script("undefined_global.lox")
+        .code(70)
+        .stderr("Undefined variable 'undefined'.\n[line 1] in script\nRuntime error\n");
+}
+
+#[test]
+fn test_repl_keeps_globals() {
+    assert_cmd::Command::cargo_bin(env!["CARGO_PKG_NAME"])
+        .unwrap()
+        .write_stdin("var a = 1;\nprint oops;\nprint a + 1;\n")
+        .assert()
+        .success()
+        .stdout("> > > 2\n> \n");
+}
+
+#[test]
+fn test_locals() {
+    run_script("locals.lox")
+        .success()
+        .stdout("inner a and global b\nreassigned\nouter a\nglobal a\n");
+}
+
+#[test]
+fn test_local_redeclared() {
+    run_script("local_redeclared.lox").code(65).stderr(
+        "[line 3] Error at 'a': Already a variable with this name in this scope.\nCompile error\n",
+    );
+}
+
+#[test]
+fn test_local_own_initializer() {
+    run_script("local_own_initializer.lox").code(65).stderr(
+        "[line 3] Error at 'a': Can't read local variable in its own initializer.\nCompile error\n",
+    );
+}
+
+#[test]
+fn test_control_flow() {
+    run_script("control_flow.lox")
+        .success()
+        .stdout("0\n1\n1\n2\n3\n5\n8\n13\n21\n34\n55\n89\n3\ntwo\n1\ndefault\nfalse\n2\n");
+}
+
+#[test]
+fn test_functions() {
+    run_script("fib.lox").success().stdout(
+        "1\n1\n2\n3\n5\n8\n13\n21\n34\n55\n89\n144\n233\n377\n610\n987\n1597\n2584\n4181\n",
+    );
+}
+
+#[test]
+fn test_stack_trace() {
+    run_script("stack_trace.lox")
+        .code(70)
+        .stdout("<fn a>\n")
+        .stderr(
+            "Operands must be two numbers or two strings.\n\
+         [line 3] in c()\n\
+         [line 2] in b()\n\
+         [line 1] in a()\n\
+         [line 5] in script\n\
+         Runtime error\n",
+        );
+}
+
+#[test]
+fn test_counter() {
+    run_script("counter.lox").success().stdout("1\n2\n");
+}
+
+#[test]
+fn test_closures() {
+    run_script("closures.lox")
+        .success()
+        .stdout("updated\nreturn from outer\ncreate inner closure\nvalue\n3\n13\n0\n1\n");
+}
+
+#[test]
+fn test_classes() {
+    run_script("classes.lox").success().stdout(
+        "Basic class, no properties, method calls, or instantiation.\n\
+         DevonshireCream\n\
+         \n\
+         Class instantiation.\n\
+         Bagel instance\n\
+         \n\
+         Method calls.\n\
+         Crunch, crunch, crunch\n\
+         \n\
+         Method closures.\n\
+         Egoist instance\n\
+         Egoist instance\n\
+         \n\
+         Properties and closures.\n\
+         The German chocolate cake is delicious!\n\
+         \n\
+         Callbacks.\n\
+         Thing instance\n\
+         \n\
+         initializer\n\
+         Foo instance\n\
+         Greetings, Zaphod\n\
+         Foo instance\n\
+         Foo instance\n\
+         Foo instance\n\
+         \n\
+         inheritance\n",
+    );
+}
+
+#[test]
+fn test_this_outside_class() {
+    run_script("this_outside_class.lox")
+        .code(65)
+        .stderr("[line 1] Error at 'this': Can't use 'this' outside of a class.\nCompile error\n");
+}
+
+#[test]
+fn test_return_from_init() {
+    run_script("return_from_init.lox").code(65).stderr(
+        "[line 3] Error at 'return': Can't return a value from an initializer.\nCompile error\n",
+    );
+}
+
+#[test]
+fn test_undefined_property() {
+    run_script("undefined_property.lox")
+        .code(70)
+        .stderr("Undefined property 'missing'.\n[line 2] in script\nRuntime error\n");
+}
+
+#[test]
+fn test_inheritance() {
+    run_script("inheritance.lox").success().stdout(
+        "Fry until golden\n\
+         Pipe full of custard and coat with chocolate.\n\
+         A doughnut with custard and chocolate\n",
+    );
+}
+
+#[test]
+fn test_inherit_self() {
+    run_script("inherit_self.lox")
+        .code(65)
+        .stderr("[line 1] Error at 'Foo': A class can't inherit from itself.\nCompile error\n");
+}
+
+#[test]
+fn test_super_without_superclass() {
+    run_script("super_without_superclass.lox").code(65).stderr(
+        "[line 3] Error at 'super': Can't use 'super' in a class with no superclass.\n\
+         Compile error\n",
+    );
+}
+
+#[test]
+fn test_invoke() {
+    run_script("invoke.lox")
+        .code(70)
+        .stdout("Hello, Ada\nHello, Grace!\npsst, Ada\npsst, Grace!\n")
+        .stderr("Only instances have methods.\n[line 28] in script\nRuntime error\n");
+}
+
+#[test]
+fn test_natives() {
+    run_script("natives.lox")
+        .code(70)
+        .stdout("<native fn>\ntrue\n12.5!\nnil\nsame\n43\n3.5\nnil\n5\n4\n2\n3\ntrue\ntrue\n")
+        .stderr("Argument must be a string.\n[line 20] in script\nRuntime error\n");
+}
+
+#[test]
+fn test_input_and_exit() {
+    assert_cmd::Command::cargo_bin(env!["CARGO_PKG_NAME"])
+        .unwrap()
+        .arg("--file")
+        .arg("tests/scripts/input.lox")
+        .write_stdin("Ada\n")
+        .assert()
+        .code(3)
+        .stdout("hi Ada\nnil\n");
+}
+
+#[test]
+fn test_compile_errors() {
+    run_script("compile_errors.lox").code(65).stderr(
+        "[line 1] Error at ';': Expect expression.\n\
+         [line 2] Error at ';': Expect expression.\n\
+         [line 4] Error: Unexpected character '@'.\n\
+         [line 5] Error at '{': Expect class name.\n\
+         Compile error\n",
+    );
+}
+
+#[test]
+fn test_snippets() {
+    Command::cargo_bin(env!["CARGO_PKG_NAME"])
+        .unwrap()
+        .arg("--snippets")
+        .arg("--file")
+        .arg("tests/scripts/this_outside_class.lox")
+        .assert()
+        .code(65)
+        .stderr(
+            "error: Can't use 'this' outside of a class.\n \
+             --> line 1, column 7\n  \
+             |\n\
+             1 | print this;\n  \
+             |       ^^^^\n\
+             Compile error\n",
+        );
+}
+
+#[test]
+fn test_runtime_error_snippets() {
+    Command::cargo_bin(env!["CARGO_PKG_NAME"])
+        .unwrap()
+        .arg("--snippets")
+        .arg("--file")
+        .arg("tests/scripts/undefined_property.lox")
+        .assert()
+        .code(70)
+        .stderr(
+            "error: Undefined property 'missing'.\n \
+             --> line 2, column 13\n  \
+             |\n\
+             2 | print Foo().missing;\n  \
+             |             ^^^^^^^\n\
+             [line 2] in script\n\
+             Runtime error\n",
+        );
+}
+
+#[test]
+fn test_repl_snippets_from_an_earlier_line() {
+    // `f` fails while running the second line, but its code is from the
+    // first, so there's no snippet to show.
+    assert_cmd::Command::cargo_bin(env!["CARGO_PKG_NAME"])
+        .unwrap()
+        .arg("--snippets")
+        .write_stdin("fun f() { return 1 + nil; }\nprint \"a long string here\"; f();\nf(;\n")
+        .assert()
+        .success()
+        .stdout("> > a long string here\n> > \n")
+        .stderr(
+            "Operands must be two numbers or two strings.\n\
+             [line 1] in f()\n\
+             [line 1] in script\n\
+             Runtime error\n\
+             error: Expect expression.\n \
+             --> line 1, column 3\n  \
+             |\n\
+             1 | f(;\n  \
+             |   ^\n\
+             Compile error\n",
+        );
+}
+
+#[test]
+fn test_unicode() {
+    run_script("unicode.lox")
+        .success()
+        .stdout("héllo, wörld ✓\n14\n名前は☕\n2\ntab\tand «quotes»\n");
+}
+
+#[test]
+fn test_long_constants() {
+    run_script("long_constants.lox")
+        .success()
+        .stdout("45150\ndone\n");
+}
+
+#[test]
+fn test_many_globals() {
+    run_script("many_globals.lox")
+        .success()
+        .stdout("300\n300.5\n44850\nfield\nbase derived\nbase\n");
+}
+
+#[test]
+fn test_deep_recursion() {
+    let assert = run_script("deep_recursion.lox").code(70);
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+
+    assert!(stderr.starts_with("Stack overflow.\n[line 2] in recurse()\n"));
+    assert!(stderr.ends_with("[line 4] in script\nRuntime error\n"));
+}
+
+#[test]
+fn test_compile_and_run_bytecode() {
+    let output = std::env::temp_dir().join(format!("classes-{}.loxc", std::process::id()));
+    Command::cargo_bin(env!["CARGO_PKG_NAME"])
+        .unwrap()
+        .arg("compile")
+        .arg("tests/scripts/classes.lox")
+        .arg("--output")
+        .arg(&output)
+        .assert()
+        .success();
+
+    let expected = run_script("classes.lox")
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    Command::cargo_bin(env!["CARGO_PKG_NAME"])
+        .unwrap()
+        .arg("--file")
+        .arg(&output)
+        .assert()
+        .success()
+        .stdout(expected);
+
+    std::fs::remove_file(output).unwrap();
+}
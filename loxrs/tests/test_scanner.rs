@@ -0,0 +1,123 @@
+use pretty_assertions::assert_eq;
+
+use loxrs::error::Error;
+use loxrs::scanner::{Scanner, TokenType};
+
+/// Scan everything, returning each token's type, line, column and span.
+fn positions(source: &str) -> Vec<(TokenType, usize, usize, std::ops::Range<usize>)> {
+    Scanner::new(source.to_string())
+        .tokens()
+        .unwrap()
+        .iter()
+        .map(|token| (token.ty, token.line, token.column, token.span()))
+        .collect()
+}
+
+#[test]
+fn test_columns_and_spans() {
+    assert_eq!(
+        positions("var a = 1;\n  print a;"),
+        vec![
+            (TokenType::Var, 1, 1, 0..3),
+            (TokenType::Identifier, 1, 5, 4..5),
+            (TokenType::Equal, 1, 7, 6..7),
+            (TokenType::Number, 1, 9, 8..9),
+            (TokenType::Semicolon, 1, 10, 9..10),
+            (TokenType::Print, 2, 3, 13..18),
+            (TokenType::Identifier, 2, 9, 19..20),
+            (TokenType::Semicolon, 2, 10, 20..21),
+            (TokenType::EOF, 2, 11, 21..21),
+        ]
+    );
+}
+
+#[test]
+fn test_columns_after_comments_and_multiline_strings() {
+    assert_eq!(
+        positions("// note\n\"a\nb\" x"),
+        vec![
+            (TokenType::String, 3, 1, 8..13),
+            (TokenType::Identifier, 3, 4, 14..15),
+            (TokenType::EOF, 3, 5, 15..15),
+        ]
+    );
+}
+
+#[test]
+fn test_multi_byte_strings_and_comments() {
+    assert_eq!(
+        positions("// ünïcödé 🎉\n\"héllo ✓\" x"),
+        vec![
+            (TokenType::String, 2, 1, 20..32),
+            (TokenType::Identifier, 2, 11, 33..34),
+            (TokenType::EOF, 2, 12, 34..34),
+        ]
+    );
+}
+
+#[test]
+fn test_unicode_identifiers() {
+    let source = "var café = 名前;";
+    let texts: Vec<_> = Scanner::new(source.to_string())
+        .map(|token| token.unwrap())
+        .take_while(|token| token.ty != TokenType::EOF)
+        .map(|token| (token.ty, token.text(source).to_string(), token.column))
+        .collect();
+
+    assert_eq!(
+        texts,
+        vec![
+            (TokenType::Var, "var".to_string(), 1),
+            (TokenType::Identifier, "café".to_string(), 5),
+            (TokenType::Equal, "=".to_string(), 10),
+            (TokenType::Identifier, "名前".to_string(), 12),
+            (TokenType::Semicolon, ";".to_string(), 14),
+        ]
+    );
+}
+
+#[test]
+fn test_unexpected_multi_byte_character() {
+    let mut scanner = Scanner::new("1 § 2".to_string());
+    scanner.scan_token().unwrap();
+
+    match scanner.scan_token() {
+        Err(Error::ScanError(diagnostic)) => {
+            assert_eq!(diagnostic.message, "Unexpected character '§'.");
+            assert_eq!(diagnostic.column, 3);
+            assert_eq!(diagnostic.span, 2..4);
+        }
+        other => panic!("expected a scan error, got {:?}", other),
+    }
+    assert_eq!(scanner.scan_token().unwrap().ty, TokenType::Number);
+}
+
+#[test]
+fn test_iterator_ends_after_one_eof() {
+    let mut scanner = Scanner::new("print".to_string());
+
+    assert_eq!(scanner.next().unwrap().unwrap().ty, TokenType::Print);
+    assert_eq!(scanner.next().unwrap().unwrap().ty, TokenType::EOF);
+    assert!(scanner.next().is_none());
+    assert!(scanner.next().is_none());
+}
+
+#[test]
+fn test_iterator_yields_errors_in_place() {
+    let results: Vec<_> = Scanner::new("a @ b".to_string()).collect();
+
+    assert_eq!(results.len(), 4);
+    assert!(matches!(results[1], Err(Error::ScanError(_))));
+    assert_eq!(results[2].as_ref().unwrap().ty, TokenType::Identifier);
+    assert_eq!(results[3].as_ref().unwrap().ty, TokenType::EOF);
+}
+
+#[test]
+fn test_tokens_stops_at_first_error() {
+    match Scanner::new("a @ b $".to_string()).tokens() {
+        Err(Error::ScanError(diagnostic)) => {
+            assert_eq!(diagnostic.message, "Unexpected character '@'.")
+        }
+        other => panic!("expected a scan error, got {:?}", other),
+    }
+}
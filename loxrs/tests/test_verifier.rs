@@ -0,0 +1,352 @@
+use std::fs;
+use std::rc::Rc;
+
+use pretty_assertions::assert_eq;
+
+use loxrs::bytecode;
+use loxrs::chunk::{Chunk, OpCode};
+use loxrs::error::Error;
+use loxrs::object::{Obj, ObjFunction, ObjRef};
+use loxrs::value::Value;
+use loxrs::verifier::{VerifyError, VerifyErrorKind, NESTING_MAX};
+use loxrs::vm::VM;
+
+use OpCode::*;
+
+/// A function whose chunk is `code`, with one line and no spans to speak of.
+fn function(vm: &mut VM, name: Option<&str>, code: &[u8], constants: &[Value]) -> ObjRef {
+    let mut chunk = Chunk::new();
+    for &byte in code {
+        chunk.write(byte, 1, 0..0);
+    }
+    chunk.constants.extend_from_slice(constants);
+
+    let name = name.map(|name| vm.heap.intern(name));
+    let mut function = ObjFunction::new(name);
+    function.chunk = Rc::new(chunk);
+    vm.heap.alloc(Obj::Function(function))
+}
+
+fn verify_error(code: &[u8], constants: &[Value]) -> VerifyError {
+    let mut vm = VM::new();
+    let function = function(&mut vm, None, code, constants);
+    match vm.load_function(function) {
+        Err(Error::VerifyError(err)) => err,
+        other => panic!("expected a verify error, got {:?}", other.map(|_| ())),
+    }
+}
+
+fn kind(code: &[u8], constants: &[Value]) -> VerifyErrorKind {
+    verify_error(code, constants).kind
+}
+
+#[test]
+fn test_compiled_scripts_verify() {
+    for entry in fs::read_dir("tests/scripts").unwrap() {
+        let path = entry.unwrap().path();
+        let source = fs::read_to_string(&path).unwrap();
+        let mut vm = VM::new();
+        let script = match vm.compile(&source) {
+            Ok(script) => script,
+            Err(_) => continue,
+        };
+
+        let bytes = bytecode::save(&vm, &script).unwrap();
+        if let Err(err) = bytecode::load(&mut VM::new(), &bytes) {
+            panic!("{}: {}", path.display(), err);
+        }
+    }
+}
+
+#[test]
+fn test_hand_built_function_runs() {
+    let mut vm = VM::new();
+    let code = [
+        OpConstant as u8,
+        0,
+        OpDefineGlobal as u8,
+        1,
+        OpNil as u8,
+        OpReturn as u8,
+    ];
+    let name = Value::Obj(vm.heap.intern("answer"));
+    let function = function(&mut vm, None, &code, &[Value::Number(42.0), name]);
+
+    let script = vm.load_function(function).unwrap();
+    vm.run_script(&script).unwrap();
+
+    let name = vm.heap.intern("answer");
+    assert_eq!(vm.globals.get(&name), Some(&Value::Number(42.0)));
+}
+
+#[test]
+fn test_invalid_opcode() {
+    assert_eq!(kind(&[255], &[]), VerifyErrorKind::InvalidOpCode(255));
+}
+
+#[test]
+fn test_truncated_operand() {
+    assert_eq!(
+        kind(&[OpNil as u8, OpJump as u8, 0], &[]),
+        VerifyErrorKind::Truncated
+    );
+}
+
+#[test]
+fn test_constant_out_of_range() {
+    let err = verify_error(&[OpNil as u8, OpConstant as u8, 3, OpReturn as u8], &[]);
+    assert_eq!(err.offset, 1);
+    assert_eq!(err.kind, VerifyErrorKind::ConstantOutOfRange(3));
+}
+
+#[test]
+fn test_constant_types() {
+    let code = [OpGetGlobal as u8, 0, OpReturn as u8];
+    assert_eq!(
+        kind(&code, &[Value::Number(1.0)]),
+        VerifyErrorKind::WrongConstantType {
+            index: 0,
+            expected: "string"
+        }
+    );
+
+    let code = [OpClosure as u8, 0, OpReturn as u8];
+    assert_eq!(
+        kind(&code, &[Value::Nil]),
+        VerifyErrorKind::WrongConstantType {
+            index: 0,
+            expected: "function"
+        }
+    );
+}
+
+#[test]
+fn test_locals_and_upvalues_in_range() {
+    let code = [OpGetLocal as u8, 1, OpReturn as u8];
+    assert_eq!(kind(&code, &[]), VerifyErrorKind::LocalOutOfRange(1));
+
+    let code = [OpGetUpvalue as u8, 0, OpReturn as u8];
+    assert_eq!(kind(&code, &[]), VerifyErrorKind::UpvalueOutOfRange(0));
+}
+
+#[test]
+fn test_scripts_take_no_parameters_or_upvalues() {
+    for (arity, upvalue_count) in [(1, 0), (0, 1)] {
+        let mut vm = VM::new();
+        let code = [OpGetUpvalue as u8, 0, OpReturn as u8];
+        let script = function(&mut vm, None, &code, &[]);
+        if let Obj::Function(function) = vm.heap.get_mut(script) {
+            function.arity = arity;
+            function.upvalue_count = upvalue_count;
+        }
+
+        match vm.load_function(script) {
+            Err(Error::VerifyError(err)) => assert_eq!(err.kind, VerifyErrorKind::NotAScript),
+            other => panic!("expected a verify error, got {:?}", other.map(|_| ())),
+        }
+    }
+}
+
+/// Run code that verifies, with string constants, returning its runtime
+/// error.
+fn runtime_error(code: &[u8], constants: &[&str]) -> String {
+    let mut vm = VM::new();
+    let constants: Vec<Value> = constants
+        .iter()
+        .map(|&constant| Value::Obj(vm.heap.intern(constant)))
+        .collect();
+    let function = function(&mut vm, None, code, &constants);
+    let script = vm.load_function(function).unwrap();
+
+    let message = match vm.run_script(&script) {
+        Err(Error::RuntimeError(err)) => err.message,
+        other => panic!("expected a runtime error, got {:?}", other),
+    };
+    assert!(vm.stack.is_empty());
+    message
+}
+
+#[test]
+fn test_bad_values_are_runtime_errors() {
+    let code = [
+        OpNil as u8,
+        OpNil as u8,
+        OpGetSuper as u8,
+        0,
+        OpReturn as u8,
+    ];
+    assert_eq!(runtime_error(&code, &["m"]), "Superclass must be a class.");
+
+    let code = [
+        OpNil as u8,
+        OpNil as u8,
+        OpSuperInvoke as u8,
+        0,
+        0,
+        OpReturn as u8,
+    ];
+    assert_eq!(runtime_error(&code, &["m"]), "Superclass must be a class.");
+
+    let code = [
+        OpClass as u8,
+        0,
+        OpNil as u8,
+        OpInherit as u8,
+        OpReturn as u8,
+    ];
+    assert_eq!(runtime_error(&code, &["C"]), "Subclass must be a class.");
+
+    // A method that's a string rather than a closure.
+    let code = [
+        OpClass as u8,
+        0,
+        OpConstant as u8,
+        1,
+        OpMethod as u8,
+        2,
+        OpCall as u8,
+        0,
+        OpInvoke as u8,
+        2,
+        0,
+        OpReturn as u8,
+    ];
+    assert_eq!(
+        runtime_error(&code, &["C", "not a method", "m"]),
+        "Can only call functions and classes."
+    );
+}
+
+#[test]
+fn test_initializer_that_isnt_an_object() {
+    let code = [
+        OpClass as u8,
+        0,
+        OpNil as u8,
+        OpMethod as u8,
+        1,
+        OpNil as u8,
+        OpCall as u8,
+        1,
+        OpReturn as u8,
+    ];
+    assert_eq!(
+        runtime_error(&code, &["C", "init"]),
+        "Can only call functions and classes."
+    );
+}
+
+#[test]
+fn test_top_level_return_clears_the_stack() {
+    let mut vm = VM::new();
+    let code = [OpNil as u8, OpTrue as u8, OpFalse as u8, OpReturn as u8];
+    let function = function(&mut vm, None, &code, &[]);
+    let script = vm.load_function(function).unwrap();
+
+    vm.run_script(&script).unwrap();
+    assert!(vm.stack.is_empty());
+}
+
+#[test]
+fn test_jump_targets() {
+    let code = [OpJump as u8, 0, 9, OpNil as u8, OpReturn as u8];
+    assert_eq!(kind(&code, &[]), VerifyErrorKind::JumpOutOfBounds(Some(12)));
+
+    let code = [OpLoop as u8, 0, 9, OpNil as u8, OpReturn as u8];
+    assert_eq!(kind(&code, &[]), VerifyErrorKind::JumpOutOfBounds(None));
+
+    // Lands on the constant's operand.
+    let code = [OpJump as u8, 0, 1, OpConstant as u8, 0, OpReturn as u8];
+    assert_eq!(
+        kind(&code, &[Value::Nil]),
+        VerifyErrorKind::JumpIntoInstruction(4)
+    );
+}
+
+#[test]
+fn test_stack_underflow() {
+    let err = verify_error(&[OpNil as u8, OpAdd as u8, OpReturn as u8], &[]);
+    assert_eq!(err.offset, 1);
+    assert_eq!(
+        err.kind,
+        VerifyErrorKind::StackUnderflow {
+            depth: 1,
+            needed: 2
+        }
+    );
+}
+
+#[test]
+fn test_inconsistent_stack_depth() {
+    // One path pushes an extra nil before reaching the return.
+    let code = [
+        OpTrue as u8,
+        OpJumpIfFalse as u8,
+        0,
+        1,
+        OpNil as u8,
+        OpReturn as u8,
+    ];
+    let err = verify_error(&code, &[]);
+    assert_eq!(err.offset, 5);
+    assert!(matches!(
+        err.kind,
+        VerifyErrorKind::InconsistentStackDepth { .. }
+    ));
+}
+
+#[test]
+fn test_falls_off_end() {
+    assert_eq!(kind(&[], &[]), VerifyErrorKind::FallsOffEnd);
+    assert_eq!(kind(&[OpNil as u8], &[]), VerifyErrorKind::FallsOffEnd);
+}
+
+#[test]
+fn test_nested_functions_are_verified() {
+    let mut vm = VM::new();
+    let inner = function(
+        &mut vm,
+        Some("inner"),
+        &[OpPop as u8, OpNil as u8, OpReturn as u8],
+        &[],
+    );
+    let code = [OpClosure as u8, 0, OpReturn as u8];
+    let outer = function(&mut vm, None, &code, &[Value::Obj(inner)]);
+
+    match vm.load_function(outer) {
+        Err(Error::VerifyError(err)) => {
+            assert_eq!(err.function, Some("inner".to_string()));
+            assert_eq!(
+                err.to_string(),
+                "in inner() at offset 0: needs 1 values on the stack but there are 0"
+            );
+        }
+        other => panic!("expected a verify error, got {:?}", other.map(|_| ())),
+    }
+}
+
+/// A script with a chain of functions nested `depth` deep in its constants.
+fn nested_script(vm: &mut VM, depth: usize) -> ObjRef {
+    let code = [OpClosure as u8, 0, OpPop as u8, OpNil as u8, OpReturn as u8];
+    let mut nested = function(vm, Some("f"), &[OpNil as u8, OpReturn as u8], &[]);
+    for _ in 1..depth {
+        nested = function(vm, Some("f"), &code, &[Value::Obj(nested)]);
+    }
+    function(vm, None, &code, &[Value::Obj(nested)])
+}
+
+#[test]
+fn test_nesting_depth_is_limited() {
+    let mut vm = VM::new();
+    let script = nested_script(&mut vm, NESTING_MAX);
+    assert!(vm.load_function(script).is_ok());
+
+    let script = nested_script(&mut vm, NESTING_MAX + 1);
+    match vm.load_function(script) {
+        Err(Error::VerifyError(err)) => {
+            assert_eq!(err.function, Some("f".to_string()));
+            assert_eq!(err.kind, VerifyErrorKind::NestedTooDeeply);
+        }
+        other => panic!("expected a verify error, got {:?}", other.map(|_| ())),
+    }
+}
@@ -0,0 +1,142 @@
+use loxrs::bytecode;
+use loxrs::error::Error;
+use loxrs::value::Value;
+use loxrs::vm::VM;
+
+mod common;
+
+use common::global;
+
+fn runtime_error(vm: &mut VM, source: &str) -> String {
+    match vm.interpret(source) {
+        Err(Error::RuntimeError(err)) => err.message,
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+}
+
+/// An expression that needs `depth` values on the stack at once.
+fn nested_sum(depth: usize) -> String {
+    format!("print {}0{};", "1 + (".repeat(depth), ")".repeat(depth))
+}
+
+#[test]
+fn test_stack_grows_past_initial_capacity() {
+    let mut vm = VM::new();
+    vm.interpret(nested_sum(300)).unwrap();
+    assert!(vm.stack.is_empty());
+}
+
+#[test]
+fn test_stack_limit() {
+    let mut vm = VM::new();
+    vm.set_stack_limit(100);
+
+    assert_eq!(runtime_error(&mut vm, &nested_sum(200)), "Stack overflow.");
+    assert!(vm.stack.is_empty());
+
+    // The VM is still usable after the overflow.
+    vm.interpret(nested_sum(50)).unwrap();
+}
+
+#[test]
+fn test_frame_limit() {
+    let source = "fun count(n) { if (n > 0) count(n - 1); }";
+    let mut vm = VM::new();
+    vm.set_frame_limit(10);
+    vm.interpret(source).unwrap();
+
+    vm.interpret("count(8);").unwrap();
+    assert_eq!(runtime_error(&mut vm, "count(9);"), "Stack overflow.");
+
+    vm.set_frame_limit(1000);
+    vm.interpret("count(900);").unwrap();
+}
+
+#[test]
+fn test_run_compiled_script_many_times() {
+    let mut vm = VM::new();
+    vm.heap.set_stress_gc(true);
+    vm.interpret("var runs = 0;").unwrap();
+
+    let script = vm
+        .compile(
+            "fun greet(name) { return \"hello \" + name; }\n\
+             runs = runs + 1;\n\
+             var greeting = greet(\"run \" + str(runs));",
+        )
+        .unwrap();
+    for _ in 0..3 {
+        vm.run_script(&script).unwrap();
+        vm.collect_garbage();
+    }
+
+    assert_eq!(global(&mut vm, "runs"), Some(Value::Number(3.0)));
+    let greeting = global(&mut vm, "greeting").and_then(|value| value.as_obj());
+    assert_eq!(
+        greeting.map(|greeting| vm.heap.string_chars(greeting).to_string()),
+        Some("hello run 3".to_string())
+    );
+}
+
+#[test]
+fn test_free_script() {
+    let mut vm = VM::new();
+    vm.collect_garbage();
+    let baseline = vm.heap.object_count();
+
+    let script = vm.compile("print \"a string constant\";").unwrap();
+    vm.collect_garbage();
+    assert!(vm.heap.object_count() > baseline);
+
+    vm.free_script(script).unwrap();
+    vm.collect_garbage();
+    assert_eq!(vm.heap.object_count(), baseline);
+}
+
+#[test]
+fn test_scripts_only_run_on_their_own_vm() {
+    let mut first = VM::new();
+    let mut second = VM::new();
+    let script = first.compile("var answer = 42;").unwrap();
+
+    assert!(matches!(
+        second.run_script(&script),
+        Err(Error::ForeignScript)
+    ));
+    assert!(matches!(
+        bytecode::save(&second, &script),
+        Err(Error::ForeignScript)
+    ));
+    assert!(global(&mut second, "answer").is_none());
+
+    first.run_script(&script).unwrap();
+    assert_eq!(global(&mut first, "answer"), Some(Value::Number(42.0)));
+
+    // Saving and loading is how a script moves between VMs.
+    let bytes = bytecode::save(&first, &script).unwrap();
+    let copy = bytecode::load(&mut second, &bytes).unwrap();
+    second.run_script(&copy).unwrap();
+    assert_eq!(global(&mut second, "answer"), Some(Value::Number(42.0)));
+
+    assert!(matches!(
+        second.free_script(script),
+        Err(Error::ForeignScript)
+    ));
+}
+
+#[test]
+fn test_escaped_closures_survive_a_runtime_error() {
+    // Like a REPL session, where each line runs on the same VM.
+    let mut vm = VM::new();
+    vm.interpret("var g;").unwrap();
+    assert_eq!(
+        runtime_error(
+            &mut vm,
+            "fun f() { var x = 1; fun h() { return x; } g = h; return nil + 1; } f();",
+        ),
+        "Operands must be two numbers or two strings."
+    );
+
+    vm.interpret("var result = g();").unwrap();
+    assert_eq!(global(&mut vm, "result"), Some(Value::Number(1.0)));
+}